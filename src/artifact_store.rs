@@ -0,0 +1,392 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use aws_config::SdkConfig;
+use aws_credential_types::{
+    provider::{ProvideCredentials as _, SharedCredentialsProvider},
+    Credentials,
+};
+use aws_sigv4::{
+    http_request::{
+        sign, PercentEncodingMode, SignableBody, SignableRequest, SignatureLocation,
+        SigningSettings, UriPathNormalizationMode,
+    },
+    sign::v4,
+};
+use aws_smithy_runtime_api::client::identity::Identity;
+use hyper::{client::HttpConnector, Body, Client, Method, Request, StatusCode};
+use hyper_rustls::HttpsConnector;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::{codec::Codec, config::ArtifactStoreConfig};
+
+/// S3 のキーで、エスケープしない文字
+const KEY_SAFE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// 期限までこれより短くなった認証情報は取り直す
+const CREDENTIALS_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+pub type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+/// S3 互換のオブジェクトストレージ。
+/// Lambda 側には署名付き URL だけを渡すので、認証情報が必要なのはクライアント側だけ
+#[derive(Debug, Clone)]
+pub struct ArtifactStore {
+    config: ArtifactStoreConfig,
+    region: String,
+    credentials: CachedCredentials,
+    http: HttpClient,
+}
+
+/// SSO や AssumeRole の認証情報は実行中に期限が切れるので、署名のたびにここから取る
+#[derive(Debug, Clone)]
+pub(crate) struct CachedCredentials {
+    provider: SharedCredentialsProvider,
+    cached: Arc<tokio::sync::Mutex<Option<Credentials>>>,
+}
+
+impl CachedCredentials {
+    pub(crate) fn new(provider: SharedCredentialsProvider) -> Self {
+        Self {
+            provider,
+            cached: Arc::default(),
+        }
+    }
+
+    /// 取得済みの認証情報が期限に近ければ取り直す
+    pub(crate) async fn identity(&self) -> Result<Identity> {
+        let mut cached = self.cached.lock().await;
+        let fresh = cached.as_ref().filter(|credentials| {
+            credentials
+                .expiry()
+                .is_none_or(|expiry| expiry > SystemTime::now() + CREDENTIALS_MARGIN)
+        });
+        if let Some(credentials) = fresh {
+            return Ok(credentials.clone().into());
+        }
+
+        let credentials = self
+            .provider
+            .provide_credentials()
+            .await
+            .context("AWS の認証情報が取得できません")?;
+        *cached = Some(credentials.clone());
+        Ok(credentials.into())
+    }
+}
+
+impl ArtifactStore {
+    pub async fn new(config: &ArtifactStoreConfig, sdk_config: &SdkConfig) -> Result<Self> {
+        let region = config
+            .region
+            .clone()
+            .or_else(|| sdk_config.region().map(|r| r.to_string()))
+            .unwrap_or_else(|| "us-east-1".into());
+        let provider = sdk_config
+            .credentials_provider()
+            .context("AWS の認証情報が設定されていません")?;
+
+        let store = Self::with_provider(config, region, provider);
+        store.credentials.identity().await?;
+        Ok(store)
+    }
+
+    pub fn with_provider(
+        config: &ArtifactStoreConfig,
+        region: String,
+        provider: SharedCredentialsProvider,
+    ) -> Self {
+        Self {
+            config: config.clone(),
+            region,
+            credentials: CachedCredentials::new(provider),
+            http: http_client(),
+        }
+    }
+
+    pub fn inline_limit(&self) -> u64 {
+        self.config.inline_limit
+    }
+
+    /// 圧縮形式ごとに別のキーにする（deflate は以前と同じキー）
+    pub fn blob_key(&self, hash: &str, codec: Codec) -> String {
+        format!("{}blobs/{hash}{}", self.config.prefix, codec.extension())
+    }
+
+    pub fn collect_key(&self, subm_id: u32, casename: &str, path: &str) -> String {
+        format!(
+            "{}s_{subm_id:0>4}/c_{casename}/{}",
+            self.config.prefix,
+            path.trim_start_matches("./")
+        )
+    }
+
+    fn object_url(&self, key: &str) -> String {
+        let key = utf8_percent_encode(key, KEY_SAFE);
+        match &self.config.endpoint {
+            Some(endpoint) => format!(
+                "{}/{}/{key}",
+                endpoint.trim_end_matches('/'),
+                self.config.bucket
+            ),
+            None => format!(
+                "https://{}.s3.{}.amazonaws.com/{key}",
+                self.config.bucket, self.region
+            ),
+        }
+    }
+
+    /// 署名付き URL を作る。 method は GET / PUT / HEAD / DELETE
+    pub async fn presign(&self, method: &str, key: &str) -> Result<String> {
+        let url = self.object_url(key);
+        let identity = self.credentials.identity().await?;
+
+        let mut settings = SigningSettings::default();
+        settings.percent_encoding_mode = PercentEncodingMode::Single;
+        settings.uri_path_normalization_mode = UriPathNormalizationMode::Disabled;
+        settings.signature_location = SignatureLocation::QueryParams;
+        settings.expires_in = Some(Duration::from_secs(self.config.expires_secs));
+
+        let params = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(&self.region)
+            .name("s3")
+            .time(SystemTime::now())
+            .settings(settings)
+            .build()
+            .context("署名のパラメータが作れません")?
+            .into();
+
+        let signable = SignableRequest::new(
+            method,
+            url.as_str(),
+            std::iter::empty(),
+            SignableBody::UnsignedPayload,
+        )
+        .context("署名するリクエストが作れません")?;
+
+        let (instructions, _) = sign(signable, &params)
+            .context("リクエストに署名できません")?
+            .into_parts();
+
+        let mut request = http::Request::builder().uri(&url).body(())?;
+        instructions.apply_to_request(&mut request);
+        Ok(request.uri().to_string())
+    }
+
+    pub async fn exists(&self, key: &str) -> Result<bool> {
+        let url = self.presign("HEAD", key).await?;
+        let request = Request::builder()
+            .method(Method::HEAD)
+            .uri(url)
+            .body(Body::empty())?;
+        let response = self
+            .http
+            .request(request)
+            .await
+            .context("オブジェクトストレージに接続できません")?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            status => bail!("オブジェクト {key} の確認に失敗しました: {status}"),
+        }
+    }
+
+    pub async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        http_put(&self.http, &self.presign("PUT", key).await?, data)
+            .await
+            .with_context(|| format!("オブジェクト {key} をアップロードできません"))
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        http_get(&self.http, &self.presign("GET", key).await?)
+            .await
+            .with_context(|| format!("オブジェクト {key} をダウンロードできません"))
+    }
+
+    /// なくてもエラーにしない
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        let url = self.presign("DELETE", key).await?;
+        let request = Request::builder()
+            .method(Method::DELETE)
+            .uri(url)
+            .body(Body::empty())?;
+        let response = self
+            .http
+            .request(request)
+            .await
+            .context("オブジェクトストレージに接続できません")?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(()),
+            status if status.is_success() => Ok(()),
+            status => bail!("オブジェクト {key} を削除できません: {status}"),
+        }
+    }
+}
+
+pub fn http_client() -> HttpClient {
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder().build(https)
+}
+
+pub async fn http_get(http: &HttpClient, url: &str) -> Result<Vec<u8>> {
+    let response = http
+        .get(url.parse().context("URL が誤っています")?)
+        .await
+        .context("接続できません")?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    ensure!(
+        status.is_success(),
+        "GET が失敗しました: {status}\n{}",
+        String::from_utf8_lossy(&body)
+    );
+    Ok(body.to_vec())
+}
+
+pub async fn http_put(http: &HttpClient, url: &str, data: Vec<u8>) -> Result<()> {
+    let request = Request::builder()
+        .method(Method::PUT)
+        .uri(url)
+        .body(Body::from(data))?;
+    let response = http.request(request).await.context("接続できません")?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    ensure!(
+        status.is_success(),
+        "PUT が失敗しました: {status}\n{}",
+        String::from_utf8_lossy(&body)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use aws_credential_types::credential_fn::provide_credentials_fn;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn minio_config(bucket: &str, endpoint: &str) -> ArtifactStoreConfig {
+        serde_yaml::from_str(&format!("bucket: {bucket}\nendpoint: {endpoint}")).unwrap()
+    }
+
+    /// 呼ばれた回数を数え、 valid_for 後に期限が切れる認証情報を返す
+    pub(crate) fn counting_provider(
+        calls: Arc<AtomicUsize>,
+        valid_for: Duration,
+    ) -> SharedCredentialsProvider {
+        SharedCredentialsProvider::new(provide_credentials_fn(move || {
+            let calls = calls.clone();
+            async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Ok(Credentials::new(
+                    "AKID",
+                    "SECRET",
+                    Some("TOKEN".into()),
+                    Some(SystemTime::now() + valid_for),
+                    "test",
+                ))
+            }
+        }))
+    }
+
+    #[tokio::test]
+    async fn presign_uses_path_style_endpoint() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let store = ArtifactStore::with_provider(
+            &minio_config("bucket", "http://localhost:9000/"),
+            "us-east-1".into(),
+            counting_provider(calls, Duration::from_secs(3600)),
+        );
+        let key = store.collect_key(7, "a b", "./out.txt");
+        assert_eq!(key, "dual-judge/s_0007/c_a b/out.txt");
+
+        let url = store.presign("GET", &key).await.unwrap();
+        assert!(url.starts_with("http://localhost:9000/bucket/dual-judge/s_0007/c_a%20b/out.txt?"));
+        assert!(url.contains("X-Amz-Signature="));
+        assert!(url.contains("X-Amz-Security-Token=TOKEN"));
+    }
+
+    #[tokio::test]
+    async fn reuses_credentials_until_near_expiry() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let store = ArtifactStore::with_provider(
+            &minio_config("bucket", "http://localhost:9000"),
+            "us-east-1".into(),
+            counting_provider(calls.clone(), Duration::from_secs(3600)),
+        );
+        store.presign("GET", "a").await.unwrap();
+        store.presign("PUT", "b").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn refreshes_expiring_credentials() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let store = ArtifactStore::with_provider(
+            &minio_config("bucket", "http://localhost:9000"),
+            "us-east-1".into(),
+            counting_provider(calls.clone(), CREDENTIALS_MARGIN / 2),
+        );
+        store.presign("GET", "a").await.unwrap();
+        store.presign("GET", "a").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    /// MinIO を立てて cargo test -- --ignored で実行する（README を参照）
+    #[tokio::test]
+    #[ignore = "MinIO が必要"]
+    async fn round_trip_with_minio() {
+        let endpoint = std::env::var("DUAL_JUDGE_TEST_S3_ENDPOINT")
+            .unwrap_or_else(|_| "http://localhost:9000".into());
+        let bucket =
+            std::env::var("DUAL_JUDGE_TEST_S3_BUCKET").unwrap_or_else(|_| "dual-judge-test".into());
+        let credentials = Credentials::new(
+            std::env::var("AWS_ACCESS_KEY_ID").unwrap_or_else(|_| "minioadmin".into()),
+            std::env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_else(|_| "minioadmin".into()),
+            None,
+            None,
+            "test",
+        );
+        let store = ArtifactStore::with_provider(
+            &minio_config(&bucket, &endpoint),
+            "us-east-1".into(),
+            SharedCredentialsProvider::new(credentials),
+        );
+
+        // バケットがなければ作る（すでにあれば失敗するが構わない）
+        let _ = http_put(
+            &store.http,
+            &store.presign("PUT", "").await.unwrap(),
+            vec![],
+        )
+        .await;
+
+        let key = store.collect_key(1, "round trip", "out.txt");
+        let data = b"hello\n".repeat(1000);
+        store.put(&key, data.clone()).await.unwrap();
+        assert!(store.exists(&key).await.unwrap());
+        assert_eq!(store.get(&key).await.unwrap(), data);
+
+        // Lambda 側と同じく、署名付き URL だけで読める
+        let url = store.presign("GET", &key).await.unwrap();
+        assert_eq!(http_get(&http_client(), &url).await.unwrap(), data);
+
+        store.delete(&key).await.unwrap();
+        assert!(!store.exists(&key).await.unwrap());
+        store.delete(&key).await.unwrap();
+    }
+}
@@ -0,0 +1,458 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use aws_sdk_lambda::{primitives::Blob, types::InvocationType, Client};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    artifact_store::ArtifactStore,
+    cancel::Cancel,
+    config::Config,
+    console_styles::ConsoleStyles,
+    lambda::{self, Response, StaticBlob},
+    submission,
+    submission_state::SubmissionStateSingle::*,
+    summary::{CaseSummary, FinalSummary},
+};
+
+/// 非同期実行で投入したケースの一覧
+pub const BATCH_FILE: &str = "batch.yaml";
+/// 結果ストアでの Response のファイル名（回収ファイルと重ならないようにする）
+const RESULT_NAME: &str = "_response.json";
+
+/// 投入先。 Dir はローカルでの動作確認用で、 `bootstrap --worker <dir>` が処理する
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Queue {
+    Lambda,
+    Dir(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Batch {
+    pub queue: Queue,
+    pub submitted_at: String,
+    pub cases: Vec<BatchCase>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchCase {
+    pub name: String,
+    pub casefile: PathBuf,
+    /// Lambda ならオブジェクトストレージのキー、 Dir ならファイルのパス
+    pub result: String,
+}
+
+impl Batch {
+    pub fn save(&self, subm_dir: &Path) -> Result<()> {
+        let path = subm_dir.join(BATCH_FILE);
+        fs::write(&path, serde_yaml::to_string(self)?)
+            .with_context(|| format!("{path:?} に書き込めません"))
+    }
+
+    pub fn load(subm_dir: &Path) -> Result<Self> {
+        let path = subm_dir.join(BATCH_FILE);
+        let yaml = fs::read_to_string(&path).with_context(|| {
+            format!("{path:?} が読み込めません（非同期で実行した提出ではありません）")
+        })?;
+        Ok(serde_yaml::from_str(&yaml)?)
+    }
+}
+
+impl Queue {
+    pub fn from_config(config: &Config) -> Self {
+        match &config.lambda.batch.queue_dir {
+            Some(dir) => Queue::Dir(dir.clone()),
+            None => Queue::Lambda,
+        }
+    }
+
+    fn file_name(subm_id: u32, casename: &str) -> String {
+        format!("s_{subm_id:0>4}_c_{casename}.json")
+    }
+}
+
+/// 投入先への接続。 Lambda では結果を置くオブジェクトストレージが必須
+enum Connection {
+    Lambda {
+        client: Client,
+        store: Box<ArtifactStore>,
+    },
+    Dir(PathBuf),
+}
+
+impl Connection {
+    async fn new(queue: &Queue, config: &Config) -> Result<Self> {
+        if let Queue::Dir(dir) = queue {
+            return Ok(Connection::Dir(dir.clone()));
+        }
+
+        let store_config = config
+            .lambda
+            .artifact_store
+            .as_ref()
+            .context("非同期実行には lambda.artifact_store の設定が必要です")?;
+        let sdk_config = lambda::load_sdk_config(&config.lambda.client).await?;
+        Ok(Connection::Lambda {
+            client: Client::new(&sdk_config),
+            store: Box::new(ArtifactStore::new(store_config, &sdk_config).await?),
+        })
+    }
+
+    fn store(&self) -> Option<&ArtifactStore> {
+        match self {
+            Connection::Lambda { store, .. } => Some(store.as_ref()),
+            Connection::Dir(_) => None,
+        }
+    }
+}
+
+/// すべてのケースを投入して、結果を待たずに戻る。投入できなかったケースは飛ばして続ける
+pub async fn submit_all(
+    casefiles: &[PathBuf],
+    subm_dir: &Path,
+    config: &Config,
+    cs: &ConsoleStyles,
+    cancel: &Cancel,
+) -> Result<Batch> {
+    let queue = Queue::from_config(config);
+    let conn = Connection::new(&queue, config).await?;
+    let store = conn.store();
+    // 非同期呼び出しのペイロードは小さく、足りないファイルの再送もできないので、
+    // Lambda には静的なファイルをすべてストレージ経由で渡す
+    let blobs = lambda::prepare_blobs(config, store, store.is_some(), cs).await;
+
+    let mut batch = Batch {
+        queue,
+        submitted_at: Local::now().to_rfc3339(),
+        cases: vec![],
+    };
+    batch.save(subm_dir)?;
+
+    for casefile in casefiles {
+        if cancel.is_cancelled() {
+            break;
+        }
+        let casename = submission::case_name(casefile);
+
+        match submit(&conn, &blobs, config, &casename, casefile).await {
+            Ok(result) => {
+                println!("{}", cs.dim.apply_to(format!("投入: {casename}")));
+                batch.cases.push(BatchCase {
+                    name: casename,
+                    casefile: casefile.clone(),
+                    result,
+                });
+                // 途中で失敗しても、投入済みのケースは回収できるようにする
+                batch.save(subm_dir)?;
+            }
+            Err(e) => println!(
+                "{}",
+                cs.red
+                    .apply_to(format!("{casename} を投入できません: {e:#}"))
+            ),
+        }
+    }
+
+    Ok(batch)
+}
+
+/// 1ケースを投入して、結果の置き場所を返す
+async fn submit(
+    conn: &Connection,
+    blobs: &HashMap<PathBuf, StaticBlob>,
+    config: &Config,
+    casename: &str,
+    casefile: &Path,
+) -> Result<String> {
+    let store = conn.store();
+    let mut msg = String::new();
+    // ストレージがあれば静的なファイルは URL で渡す。ないのはローカルのワーカーなので中身を入れる
+    let Ok(mut request) = lambda::build_request(
+        config,
+        blobs,
+        store,
+        casename,
+        casefile,
+        store.is_none(),
+        &mut msg,
+    )
+    .await
+    else {
+        bail!("{}", msg.trim());
+    };
+
+    let result = match conn {
+        Connection::Lambda { store, .. } => {
+            let key = store.collect_key(config.subm_id, casename, RESULT_NAME);
+            request.result_url = Some(store.presign("PUT", &key).await?);
+            key
+        }
+        Connection::Dir(dir) => {
+            // ワーカーのカレントディレクトリは違うので絶対パスにする
+            let results = dir.join("results");
+            fs::create_dir_all(&results)?;
+            let path = fs::canonicalize(results)?.join(Queue::file_name(config.subm_id, casename));
+            request.result_url = Some(format!("file://{}", path.display()));
+            path.to_string_lossy().into_owned()
+        }
+    };
+    request.requires = request.requirements();
+
+    let payload = serde_json::to_vec(&request)?;
+    match conn {
+        Connection::Lambda { client, .. } => {
+            client
+                .invoke()
+                .function_name(&config.lambda.function_name)
+                .invocation_type(InvocationType::Event)
+                .payload(Blob::new(payload))
+                .send()
+                .await?;
+        }
+        Connection::Dir(dir) => {
+            let path = dir
+                .join("queue")
+                .join(Queue::file_name(config.subm_id, casename));
+            fs::create_dir_all(path.parent().unwrap())?;
+            // ワーカーが書きかけのファイルを読まないように、名前を変えて置く
+            let partial = path.with_extension("partial");
+            fs::write(&partial, payload)?;
+            fs::rename(&partial, &path)?;
+        }
+    }
+
+    Ok(result)
+}
+
+/// 届いている結果を回収する。 wait なら全ケースが揃うまで待つ。
+/// 未完了のケースが残っていれば incomplete な要約を返す
+pub async fn fetch(
+    subm_dir: &Path,
+    config: &Config,
+    cs: &ConsoleStyles,
+    cancel: &Cancel,
+    wait: bool,
+) -> Result<FinalSummary> {
+    let batch = Batch::load(subm_dir)?;
+    let conn = Connection::new(&batch.queue, config).await?;
+
+    loop {
+        let mut pending = 0;
+
+        for case in &batch.cases {
+            let casedir = submission::case_dir(subm_dir, &case.name);
+            if CaseSummary::load_result(&casedir).is_ok() {
+                continue;
+            }
+
+            let Some(data) = read_result(&conn, &case.result).await? else {
+                pending += 1;
+                continue;
+            };
+
+            fs::create_dir_all(&casedir)?;
+            let case_summary = receive(case, &data, &casedir, config, conn.store()).await;
+            match case_summary.save_result(&casedir) {
+                // 回収が済んだ結果はストレージに残さない
+                Ok(()) => {
+                    if let Some(store) = conn.store() {
+                        let _ = store.delete(&case.result).await;
+                    }
+                }
+                Err(e) => println!("ケースの結果が保存できません: {e:?}"),
+            }
+            if case_summary.state == AC as u32 {
+                println!("{}", case_summary);
+            } else {
+                println!("{}", cs.red.apply_to(&case_summary));
+            }
+        }
+
+        println!(
+            "{}",
+            cs.dim.apply_to(format!(
+                "回収済み {} / 未完了 {} ケース",
+                batch.cases.len() - pending,
+                pending
+            ))
+        );
+
+        if pending == 0 || !wait || cancel.is_cancelled() {
+            let mut summary = FinalSummary::zero(config.subm_id);
+            for case in &batch.cases {
+                let casedir = submission::case_dir(subm_dir, &case.name);
+                if let Ok(case_summary) = CaseSummary::load_result(&casedir) {
+                    summary.next_case(&case_summary);
+                }
+            }
+            summary.incomplete = pending > 0;
+            return Ok(summary);
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(config.lambda.batch.poll_secs)) => {}
+            _ = cancel.cancelled() => {}
+        }
+    }
+}
+
+/// まだ届いていなければ None
+async fn read_result(conn: &Connection, result: &str) -> Result<Option<Vec<u8>>> {
+    match conn {
+        Connection::Lambda { store, .. } => {
+            if !store.exists(result).await? {
+                return Ok(None);
+            }
+            Ok(Some(store.get(result).await?))
+        }
+        Connection::Dir(_) => match fs::read(result) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("結果 {result} が読み取れません")),
+        },
+    }
+}
+
+async fn receive(
+    case: &BatchCase,
+    data: &[u8],
+    casedir: &Path,
+    config: &Config,
+    store: Option<&ArtifactStore>,
+) -> CaseSummary {
+    let mut msg = String::new();
+    writeln!(
+        msg,
+        "[CLI] 提出ID: {}, ケース: {}",
+        config.subm_id, case.name
+    )
+    .unwrap();
+    writeln!(msg, "[CLI] 回収時刻: {}", Local::now()).unwrap();
+
+    let response =
+        crate::inflate(data).and_then(|json| Ok(serde_json::from_slice::<Response>(&json)?));
+
+    let result = match response {
+        Ok(response) if !response.unsupported.is_empty() => {
+            msg.push_str(&response.message);
+            writeln!(
+                msg,
+                "[CLI] [IE] bootstrap {} が {} に対応していません",
+                response.remote_version(),
+                response.unsupported.join(", ")
+            )
+            .unwrap();
+            Err(())
+        }
+        Ok(response) => {
+            lambda::receive_response(&response, config, store, &case.name, casedir, &mut msg).await
+        }
+        Err(e) => {
+            writeln!(msg, "[CLI] [IE] 結果が解釈できません: {e:#}").unwrap();
+            Err(())
+        }
+    };
+
+    if let Err(e) = fs::write(casedir.join("message.txt"), &msg) {
+        println!("message.txt に書き込めません: {e:?}");
+    }
+
+    lambda::summarize(&case.name, &case.casefile, casedir, config, result).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codec::Codec, exit_status::ExitStatus, lambda::CollectedItem};
+
+    fn cs() -> ConsoleStyles {
+        ConsoleStyles {
+            def: Default::default(),
+            cyan: Default::default(),
+            dim: Default::default(),
+            red: Default::default(),
+        }
+    }
+
+    fn put_result(path: &Path, response: &Response) {
+        let json = serde_json::to_vec(response).unwrap();
+        fs::write(path, crate::deflate(&json).unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fetch_collects_arrived_results() {
+        let temp = tempfile::tempdir().unwrap();
+        let (subm_dir, results) = (temp.path().join("subm"), temp.path().join("results"));
+        fs::create_dir_all(&subm_dir).unwrap();
+        fs::create_dir_all(&results).unwrap();
+        let config =
+            Config::load(concat!(env!("CARGO_MANIFEST_DIR"), "/judge_config.yaml")).unwrap();
+
+        let case = |name: &str| BatchCase {
+            name: name.into(),
+            casefile: format!("in/{name}.txt").into(),
+            result: results.join(name).to_string_lossy().into_owned(),
+        };
+        let batch = Batch {
+            queue: Queue::Dir(temp.path().into()),
+            submitted_at: Local::now().to_rfc3339(),
+            cases: vec![case("0000"), case("0001")],
+        };
+        batch.save(&subm_dir).unwrap();
+        assert_eq!(Batch::load(&subm_dir).unwrap(), batch);
+
+        let item = CollectedItem {
+            path: "out.txt".into(),
+            data: Codec::Deflate.encode(b"1\n", None).unwrap(),
+            uploaded: false,
+            archive: false,
+            codec: Codec::Deflate,
+        };
+        put_result(
+            &results.join("0000"),
+            &Response {
+                exit_status: Some(ExitStatus {
+                    code: Some(0),
+                    signal: None,
+                    timed_out: false,
+                    time: Some(0.1),
+                }),
+                ..Response::new("Score = 42\n".into(), vec![item])
+            },
+        );
+
+        let cancel = Cancel::new();
+        let summary = fetch(&subm_dir, &config, &cs(), &cancel, false)
+            .await
+            .unwrap();
+        assert!(summary.incomplete);
+        assert_eq!((summary.count, summary.ac_count), (1, 1));
+        assert_eq!(summary.scores, [42.]);
+        let casedir = submission::case_dir(&subm_dir, "0000");
+        assert_eq!(fs::read_to_string(casedir.join("out.txt")).unwrap(), "1\n");
+
+        // 中断された実行は IE として回収する
+        put_result(
+            &results.join("0001"),
+            &Response::failed("".into(), vec![], "容量が足りません".into()),
+        );
+        let summary = fetch(&subm_dir, &config, &cs(), &cancel, true)
+            .await
+            .unwrap();
+        assert!(!summary.incomplete);
+        assert_eq!((summary.count, summary.ac_count), (2, 1));
+        let message =
+            fs::read_to_string(submission::case_dir(&subm_dir, "0001").join("message.txt"))
+                .unwrap();
+        assert!(message.contains("[IE] bootstrap で実行が中断されました: 容量が足りません"));
+    }
+}
@@ -1,121 +1,673 @@
 #[allow(unused_imports)]
 use anyhow::{anyhow, bail, ensure, Context, Result};
 use std::{
+    collections::BTreeMap,
     env,
-    fmt::Write as _,
-    fs::{self, File},
+    fmt::{self, Write as _},
+    fs::{self, DirBuilder, File},
     io::Write as _,
-    path::Path,
+    os::unix::fs::{DirBuilderExt as _, PermissionsExt as _},
+    path::{Path, PathBuf},
     process::{Command, Stdio},
+    time::{Duration, SystemTime},
 };
 
+use base64::engine::{general_purpose, Engine as _};
+use hyper::Body;
 use lambda_runtime::{service_fn, LambdaEvent};
+use tokio::sync::mpsc::{self, UnboundedSender};
 
 use dual_judge::{
-    lambda::{CollectedItem, Request, Response},
-    now,
+    artifact_store::{self, HttpClient},
+    cancel::Cancel,
+    exit_status::ExitStatus,
+    interactive,
+    lambda::{CollectedItem, Request, Response, SendItem, StreamEvent},
+    now, transfer,
 };
 
 const TMP_DIR: &str = "/tmp/";
+/// この下に呼び出しごとの実行ディレクトリを作る
 const RUN_DIR: &str = "/tmp/runner/";
+const BLOB_DIR: &str = "/tmp/blobs/";
+/// キャッシュの上限 (MB) 。超えたら使われていない順に消す
+const BLOB_CACHE_MB_ENV: &str = "DUAL_JUDGE_BLOB_CACHE_MB";
+/// /tmp の既定は 512 MB なので、実行ディレクトリの分を残す
+const DEFAULT_BLOB_CACHE_MB: u64 = 256;
+/// 設定すると、関数 URL から呼ばれたときに応答をストリームで返す。
+/// 関数 URL の InvokeMode も RESPONSE_STREAM にしておく
+const STREAMING_ENV: &str = "DUAL_JUDGE_STREAMING";
+/// Lambda の実行環境にだけある環境変数
+const RUNTIME_API_ENV: &str = "AWS_LAMBDA_RUNTIME_API";
+/// 1 にすると Lambda の外でも実行する（手元での動作確認用。 /tmp/runner/ を消し、任意のコマンドを実行する）
+const ALLOW_OUTSIDE_ENV: &str = "DUAL_JUDGE_ALLOW_OUTSIDE_LAMBDA";
+/// --self-test で存在を確認する追加のコマンド（空白区切り）
+const RUNTIMES_ENV: &str = "DUAL_JUDGE_RUNTIMES";
+/// --self-test で必ず確認するコマンド（コンテナイメージに入れている言語）
+const DEFAULT_RUNTIMES: &[&str] = &["dotnet"];
 
 #[tokio::main]
 async fn main() -> Result<(), lambda_runtime::Error> {
-    return lambda_runtime::run(service_fn(handler)).await;
+    let args: Vec<_> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--self-test") {
+        if !self_test() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if args.get(1).map(String::as_str) == Some("--worker") {
+        let dir = args
+            .get(2)
+            .ok_or("キューのディレクトリを指定してください: bootstrap --worker <dir>")?;
+        if env::var(ALLOW_OUTSIDE_ENV).as_deref() != Ok("1") {
+            return Err(format!(
+                "--worker は任意のコマンドを実行するので、 {ALLOW_OUTSIDE_ENV}=1 を設定したときだけ動きます"
+            )
+            .into());
+        }
+        return Ok(worker(Path::new(dir)).await?);
+    }
+
+    if env::var_os(RUNTIME_API_ENV).is_none() && env::var(ALLOW_OUTSIDE_ENV).as_deref() != Ok("1") {
+        return Err(format!(
+            "AWS Lambda の外では実行しません（{RUNTIME_API_ENV} がありません）。\
+             手元で試すなら {ALLOW_OUTSIDE_ENV}=1 を設定してください"
+        )
+        .into());
+    }
+
+    // タイムアウトで打ち切られた呼び出しの実行ディレクトリが残っていれば消す
+    if env::var_os(RUNTIME_API_ENV).is_some() {
+        let _ = fs::remove_dir_all(RUN_DIR);
+    }
+
+    if env::var_os(STREAMING_ENV).is_some() {
+        lambda_runtime::run_with_streaming_response(service_fn(stream_handler)).await
+    } else {
+        lambda_runtime::run(service_fn(handler)).await
+    }
 }
 
-async fn handler(event: LambdaEvent<Request>) -> Result<Response> {
-    let request = event.payload;
-    let mut log = String::new();
+async fn handler(event: LambdaEvent<serde_json::Value>) -> Result<Response> {
+    handle(event.payload).await
+}
 
-    writeln!(log, "[AWS][{}] ディレクトリ: {RUN_DIR}", now())?;
-    fs::create_dir_all(RUN_DIR)?;
-    env::set_current_dir(RUN_DIR)?;
+/// 失敗しても Lambda のエラーにはせず、 error を入れた Response を返す。
+/// result_url があれば（非同期実行）結果はそこに置く。
+/// 置けなかったときも、非同期の Invoke が再試行されてケースを実行し直さないように、エラーにはしない
+async fn handle(payload: serde_json::Value) -> Result<Response> {
+    let request: Request = match serde_json::from_value(payload) {
+        Ok(request) => request,
+        Err(e) => return Ok(invalid_request(e.into())),
+    };
+    let result_url = request.result_url.clone();
+    let response = serve(request, Log::default()).await;
+    let Some(url) = result_url else {
+        return Ok(response);
+    };
+    Ok(deliver(&url, response).await.unwrap_or_else(|e| {
+        let error = format!("結果が置けません: {e:#}");
+        println!("{error}");
+        Response::failed(format!("[AWS][{}][IE] {error}\n", now()), vec![], error)
+    }))
+}
 
-    writeln!(log, "[AWS][{}] ファイルの展開", now())?;
-    for sent in &request.send {
-        dual_judge::decode_file(&sent.data, &sent.path).or_else(|e| {
-            writeln!(
-                log,
-                "[AWS][{}][IE] {:?} に展開できません: {e:?}",
-                now(),
-                sent.path,
-            )
-        })?;
+fn invalid_request(e: anyhow::Error) -> Response {
+    let error = format!("リクエストが解釈できません（judge と bootstrap のバージョンが合っていない可能性があります）: {e:#}");
+    let message = format!("[AWS][{}][IE] {error}\n", now());
+    Response::failed(message, vec![], error)
+}
+
+/// コンテナイメージに必要なものが揃っているか確認する。すべて通れば true
+fn self_test() -> bool {
+    let mut ok = true;
+    let mut check = |name: &str, result: Result<String>| match result {
+        Ok(detail) => println!("[OK] {name}: {detail}"),
+        Err(e) => {
+            println!("[NG] {name}: {e:#}");
+            ok = false;
+        }
+    };
+
+    check(
+        "bootstrap",
+        Ok(format!(
+            "{} (プロトコル {})",
+            env!("CARGO_PKG_VERSION"),
+            dual_judge::lambda::PROTOCOL_VERSION
+        )),
+    );
+    check("bash", run_version("bash", &["--version"]));
+    check(TMP_DIR, tmp_writable());
+
+    let extra = env::var(RUNTIMES_ENV).unwrap_or_default();
+    let runtimes = DEFAULT_RUNTIMES
+        .iter()
+        .copied()
+        .chain(extra.split_whitespace());
+    for runtime in runtimes {
+        check(
+            runtime,
+            run_version("bash", &["-c", &format!("command -v {runtime}")]),
+        );
+    }
+
+    ok
+}
+
+/// コマンドを実行して、出力の1行目を返す
+fn run_version(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("{program} が実行できません"))?;
+    ensure!(output.status.success(), "見つかりません");
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_owned())
+}
+
+fn tmp_writable() -> Result<String> {
+    let path = Path::new(TMP_DIR).join(format!("self-test-{}", std::process::id()));
+    fs::write(&path, b"ok").context("書き込めません")?;
+    fs::remove_file(&path).context("削除できません")?;
+    Ok("書き込み可能".into())
+}
+
+/// 非同期実行の結果を result_url に置き、呼び出し元には空の応答だけを返す
+async fn deliver(url: &str, response: Response) -> Result<Response> {
+    let data = dual_judge::deflate(&serde_json::to_vec(&response)?)?;
+
+    match url.strip_prefix("file://") {
+        Some(path) => {
+            let path = Path::new(path);
+            let partial = path.with_extension("partial");
+            fs::write(&partial, data).with_context(|| format!("{partial:?} に書き込めません"))?;
+            fs::rename(&partial, path)?;
+        }
+        None => artifact_store::http_put(&artifact_store::http_client(), url, data)
+            .await
+            .context("結果がアップロードできません")?,
+    }
+
+    Ok(Response::new(String::new(), vec![]))
+}
+
+/// 非同期実行のローカルでの代わり。 dir/queue に置かれたリクエストを1つずつ処理する
+async fn worker(dir: &Path) -> Result<()> {
+    let queue = fs::canonicalize(dir)
+        .with_context(|| format!("ディレクトリ {dir:?} がありません"))?
+        .join("queue");
+    println!("{} を監視しています", queue.display());
+
+    loop {
+        let mut entries: Vec<PathBuf> = fs::read_dir(&queue)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| Some(entry.ok()?.path()))
+                    .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+                    .collect()
+            })
+            .unwrap_or_default();
+        entries.sort();
+
+        let Some(path) = entries.first() else {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        };
+
+        // 名前を変えられたら自分が取ったということ（複数のワーカーを動かしてもよい）
+        let taken = path.with_extension("taken");
+        if fs::rename(path, &taken).is_err() {
+            continue;
+        }
+
+        let request: Request = match serde_json::from_slice(&fs::read(&taken)?) {
+            Ok(request) => request,
+            Err(e) => {
+                println!("{} が読み込めないので捨てます: {e}", path.display());
+                fs::remove_file(&taken)?;
+                continue;
+            }
+        };
+
+        let result_url = request.result_url.clone();
+        let response = serve(request, Log::default()).await;
+        match result_url {
+            Some(url) => {
+                if let Err(e) = deliver(&url, response).await {
+                    println!("{} の結果が置けません: {e:#}", path.display());
+                }
+            }
+            None => println!("{} に result_url がないので結果を捨てます", path.display()),
+        }
+        fs::remove_file(&taken)?;
+        println!("{} を処理しました", path.display());
     }
+}
+
+/// 関数 URL からの呼び出しには StreamEvent を1行ずつ返し、 Invoke からの呼び出しにはまとめて返す
+async fn stream_handler(event: LambdaEvent<serde_json::Value>) -> Result<http::Response<Body>> {
+    let payload = event.payload;
+    let url_body = payload
+        .get("requestContext")
+        .and(payload.get("body"))
+        .and_then(|body| body.as_str());
+
+    let Some(url_body) = url_body else {
+        let response = handle(payload).await?;
+        return Ok(http::Response::new(Body::from(serde_json::to_vec(
+            &response,
+        )?)));
+    };
+
+    let request: Result<Request> = if payload["isBase64Encoded"].as_bool() == Some(true) {
+        general_purpose::STANDARD
+            .decode(url_body)
+            .map_err(anyhow::Error::from)
+            .and_then(|body| Ok(serde_json::from_slice(&body)?))
+    } else {
+        serde_json::from_str(url_body).map_err(anyhow::Error::from)
+    };
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (mut body_tx, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let response = match request {
+            Ok(request) => serve(request, Log::streaming(tx.clone())).await,
+            Err(e) => invalid_request(e),
+        };
+        let _ = tx.send(StreamEvent::Done { response });
+    });
+
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            let Ok(mut line) = serde_json::to_vec(&event) else {
+                continue;
+            };
+            line.push(b'\n');
+            if body_tx.send_data(line.into()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(http::Response::builder()
+        .header("content-type", "application/x-ndjson")
+        .body(body)?)
+}
+
+/// 実行ログ。ストリーミング中は1行ごとにクライアントへ送る
+#[derive(Default)]
+struct Log {
+    text: String,
+    tx: Option<UnboundedSender<StreamEvent>>,
+}
+
+impl Log {
+    fn streaming(tx: UnboundedSender<StreamEvent>) -> Self {
+        Log {
+            text: String::new(),
+            tx: Some(tx),
+        }
+    }
+
+    /// ストリーミング中ならすぐに送り、そうでなければ collected にためる
+    fn collected(&mut self, item: CollectedItem, collected: &mut Vec<CollectedItem>) {
+        match &self.tx {
+            Some(tx) => {
+                let _ = tx.send(StreamEvent::Collected { item });
+            }
+            None => collected.push(item),
+        }
+    }
+
+    fn response(self, collected: Vec<CollectedItem>) -> Response {
+        Response::new(self.text, collected)
+    }
+}
 
-    writeln!(log, "[AWS][{}] 実行ディレクトリに実行権限を付与", now())?;
-    chmod_rec(Path::new(RUN_DIR)).or_else(|e| {
+impl fmt::Write for Log {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match &self.tx {
+            Some(tx) => {
+                // 送信済みの行は text から取り除く
+                self.text.push_str(s);
+                while let Some(i) = self.text.find('\n') {
+                    let rest = self.text.split_off(i + 1);
+                    let line = std::mem::replace(&mut self.text, rest);
+                    let _ = tx.send(StreamEvent::Log { line });
+                }
+            }
+            None => self.text.push_str(s),
+        }
+        Ok(())
+    }
+}
+
+/// 途中で失敗したら、そこまでのログと回収ファイルに error を添えて返す
+async fn serve(request: Request, mut log: Log) -> Response {
+    if request.probe {
+        return log.response(vec![]);
+    }
+
+    let unsupported = Response::unsupported(&request.requires);
+    if !unsupported.is_empty() {
         writeln!(
             log,
-            "[AWS][{}][IE] {RUN_DIR} の権限が変更できません: {e:?}",
-            now()
+            "[AWS][{}][IE] bootstrap {} は {} に対応していません",
+            now(),
+            env!("CARGO_PKG_VERSION"),
+            unsupported.join(", ")
         )
-    })?;
+        .unwrap();
+        return Response {
+            unsupported,
+            ..log.response(vec![])
+        };
+    }
 
-    writeln!(log, "[AWS][{}] コマンドの実行", now())?;
-    exec_start_sh().or_else(|e| {
+    let mut missing = vec![];
+    for sent in &request.send {
+        let Some(hash) = &sent.hash else { continue };
+        let blob = match blob_path(hash) {
+            Ok(blob) => blob,
+            Err(e) => {
+                writeln!(log, "[AWS][{}][IE] {e:#}", now()).unwrap();
+                return Response {
+                    error: Some(format!("{e:#}")),
+                    ..log.response(vec![])
+                };
+            }
+        };
+        if sent.data.is_empty() && sent.url.is_none() && !blob.is_file() {
+            missing.push(hash.clone());
+        }
+    }
+    if !missing.is_empty() {
         writeln!(
             log,
-            "[AWS][{}][IE] {RUN_DIR} start.sh を正常に実行できません: {e:?}",
-            now()
+            "[AWS][{}] キャッシュにないファイルが {} 件あります",
+            now(),
+            missing.len()
         )
-    })?;
+        .unwrap();
+        return Response {
+            missing,
+            ..log.response(vec![])
+        };
+    }
 
-    writeln!(log, "[AWS][{}] ファイルの回収", now())?;
     let mut collected = vec![];
+    match execute(&request, &mut log, &mut collected).await {
+        Ok(exit_status) => {
+            writeln!(log, "[AWS][{}] 実行完了", now()).unwrap();
+            Response {
+                exit_status,
+                ..log.response(collected)
+            }
+        }
+        Err(e) => {
+            writeln!(log, "[AWS][{}][IE] 実行が中断されました: {e:#}", now()).unwrap();
+            Response {
+                error: Some(format!("{e:#}")),
+                ..log.response(collected)
+            }
+        }
+    }
+}
+
+async fn execute(
+    request: &Request,
+    log: &mut Log,
+    collected: &mut Vec<CollectedItem>,
+) -> Result<Option<ExitStatus>> {
+    // 実行ディレクトリは所有者だけが読み書きできるようにする。
+    // 呼び出しごとに新しく作り、途中で失敗しても drop で消える
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(RUN_DIR)
+        .with_context(|| format!("{RUN_DIR} が作成できません"))?;
+    fs::set_permissions(RUN_DIR, fs::Permissions::from_mode(0o700))
+        .with_context(|| format!("{RUN_DIR} の権限が変更できません"))?;
+    let run_dir = tempfile::Builder::new()
+        .prefix("run-")
+        .tempdir_in(RUN_DIR)
+        .context("実行ディレクトリが作成できません")?;
+    let dir = run_dir.path();
+    writeln!(log, "[AWS][{}] ディレクトリ: {}", now(), dir.display())?;
+
+    let http = artifact_store::http_client();
+
+    writeln!(log, "[AWS][{}] ファイルの展開", now())?;
+    for sent in &request.send {
+        expand(sent, dir, &http)
+            .await
+            .with_context(|| format!("{:?} に展開できません", sent.path))?;
+    }
+
+    writeln!(log, "[AWS][{}] コマンドの実行", now())?;
+    let exit_status = match &request.interactive {
+        Some(interactive) => {
+            match interactive::run(interactive, dir, &request.env, &Cancel::new()).await {
+                Ok(outcome) => {
+                    writeln!(log, "[AWS][{}] 対話の終了: {outcome}", now())?;
+                    if let Some(verdict) = outcome.verdict() {
+                        writeln!(log, "[AWS][{}]{verdict}", now())?;
+                    }
+                    Some(outcome.solution)
+                }
+                Err(e) => {
+                    writeln!(log, "[AWS][{}][IE] 対話型の実行ができません: {e:#}", now())?;
+                    None
+                }
+            }
+        }
+        None => match exec_start_sh(dir, &request.env).await {
+            Ok(status) => {
+                writeln!(log, "[AWS][{}] start.sh の終了: {status}", now())?;
+                Some(status)
+            }
+            Err(e) => {
+                writeln!(
+                    log,
+                    "[AWS][{}][IE] start.sh を正常に実行できません: {e:#}",
+                    now()
+                )?;
+                None
+            }
+        },
+    };
+
+    writeln!(log, "[AWS][{}] ファイルの回収", now())?;
     for path in &request.collect {
-        match dual_judge::encode_file(&path) {
-            Ok(s) => collected.push(CollectedItem {
-                path: path.clone(),
-                data: s,
-            }),
+        match collect(path, dir, request, &http).await {
+            Ok(item) => log.collected(item, collected),
             Err(e) => {
                 writeln!(
                     log,
-                    "[AWS][{}] {path:?} が回収できませんが続行します: {e:?}",
+                    "[AWS][{}] {path:?} が回収できませんが続行します: {e:#}",
                     now()
                 )?;
             }
         }
     }
 
-    env::set_current_dir(TMP_DIR)?;
-    fs::remove_dir_all(RUN_DIR)?;
-    writeln!(log, "[AWS][{}] 実行完了", now())?;
+    if let Err(e) = run_dir.close() {
+        writeln!(
+            log,
+            "[AWS][{}] 実行ディレクトリが削除できません: {e}",
+            now()
+        )?;
+    }
 
-    Ok(Response {
-        message: log,
-        collected,
-    })
+    Ok(exit_status)
 }
 
-fn chmod_rec(path: &Path) -> Result<()> {
-    let output = Command::new("chmod")
-        .arg("-R")
-        .arg("777")
-        .arg(format!("{}", path.display()))
-        .spawn()?
-        .wait_with_output()?;
+async fn expand(sent: &SendItem, dir: &Path, http: &HttpClient) -> Result<()> {
+    let path = dir.join(&sent.path);
+    let blob = sent.hash.as_deref().map(blob_path).transpose()?;
 
-    if output.status.success() {
-        Ok(())
+    let bytes = match (&blob, &sent.url) {
+        (Some(blob), _) if sent.data.is_empty() && blob.is_file() => {
+            let bytes =
+                fs::read(blob).with_context(|| format!("キャッシュ {blob:?} が読み取れません"))?;
+            // 更新時刻を最後に使った時刻として、古いものから消す
+            let _ = File::options()
+                .write(true)
+                .open(blob)
+                .and_then(|f| f.set_modified(SystemTime::now()));
+            bytes
+        }
+        (_, Some(url)) if sent.data.is_empty() => {
+            let compressed = artifact_store::http_get(http, url)
+                .await
+                .with_context(|| format!("{:?} の内容がダウンロードできません", sent.path))?;
+            sent.codec.decompress(&compressed).with_context(|| {
+                format!("データのデコードが失敗しました（パス: {:?}）", sent.path)
+            })?
+        }
+        _ => sent
+            .codec
+            .decode(sent.data.as_bytes())
+            .with_context(|| format!("データのデコードが失敗しました（パス: {:?}）", sent.path))?,
+    };
+
+    if sent.archive {
+        transfer::unpack(&bytes, &path)?;
     } else {
-        Err(anyhow!(
-            "\"chmod 777 -R {}\" failed: \n{}\n\n{}",
-            path.display(),
-            String::from_utf8_lossy(&output.stdout),
-            String::from_utf8_lossy(&output.stderr)
-        ))
+        fs::write(&path, &bytes)
+            .with_context(|| format!("デコード先ファイル {:?} に書き込めません", sent.path))?;
+        // Windows のクライアントや古いクライアントは権限を送らないので、以前と同じく実行できるようにする
+        transfer::set_mode(&path, sent.mode.unwrap_or(transfer::DEFAULT_MODE))?;
+        if let Some(mtime) = sent.mtime {
+            transfer::set_mtime(&path, mtime)?;
+        }
+    }
+
+    // キャッシュへの保存に失敗しても、次回また送られてくるだけなので無視する
+    if let (Some(blob), Some(hash)) = (&blob, &sent.hash) {
+        let limit = blob_cache_limit();
+        if !blob.is_file()
+            && (bytes.len() as u64) <= limit
+            && dual_judge::sha256(&bytes) == *hash
+            && fs::create_dir_all(BLOB_DIR).is_ok()
+        {
+            evict_blobs(limit - bytes.len() as u64);
+            let partial = blob.with_extension("partial");
+            if fs::write(&partial, &bytes).is_ok() {
+                let _ = fs::rename(&partial, blob);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// キャッシュのパス。 hash はクライアントから届くので、 BLOB_DIR の外を指さないように確かめる
+fn blob_path(hash: &str) -> Result<PathBuf> {
+    ensure!(
+        hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')),
+        "ハッシュが SHA-256 の16進数ではありません: {hash:?}"
+    );
+    Ok(Path::new(BLOB_DIR).join(hash))
+}
+
+fn blob_cache_limit() -> u64 {
+    env::var(BLOB_CACHE_MB_ENV)
+        .ok()
+        .and_then(|mb| mb.parse().ok())
+        .unwrap_or(DEFAULT_BLOB_CACHE_MB)
+        * 1024
+        * 1024
+}
+
+/// キャッシュの合計が limit 以下になるまで、使われていない順に消す
+fn evict_blobs(limit: u64) {
+    let Ok(entries) = fs::read_dir(BLOB_DIR) else {
+        return;
+    };
+    let mut blobs: Vec<_> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let metadata = entry.metadata().ok()?;
+            if !metadata.is_file() {
+                return None;
+            }
+            Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+        })
+        .collect();
+    blobs.sort();
+
+    let mut total: u64 = blobs.iter().map(|(_, len, _)| len).sum();
+    for (_, len, path) in blobs {
+        if total <= limit {
+            break;
+        }
+        if fs::remove_file(&path).is_ok() {
+            total -= len;
+        }
     }
 }
 
-fn exec_start_sh() -> Result<()> {
-    let mut outfile = File::create("./start_out.txt").context("start_out.txt が作成できません")?;
-    let mut errfile = File::create("./start_err.txt").context("start_err.txt が作成できません")?;
+async fn collect(
+    path: &Path,
+    dir: &Path,
+    request: &Request,
+    http: &HttpClient,
+) -> Result<CollectedItem> {
+    let archive = !transfer::is_single_file(dir, path);
+    let bytes = if archive {
+        transfer::pack(dir, path, None)?
+    } else {
+        fs::read(dir.join(path)).with_context(|| format!("ファイル {path:?} が読み取れません"))?
+    };
 
-    let output = Command::new("bash")
-        .arg("./start.sh")
+    let compression = request.compression.get(path).copied().unwrap_or_default();
+    let codec = compression.select(bytes.len());
+
+    if let (Some(url), Some(limit)) = (request.upload.get(path), request.inline_limit) {
+        if bytes.len() as u64 > limit {
+            artifact_store::http_put(http, url, codec.compress(&bytes, compression.level)?)
+                .await
+                .with_context(|| format!("{path:?} がアップロードできません"))?;
+            return Ok(CollectedItem {
+                path: path.into(),
+                data: String::new(),
+                uploaded: true,
+                archive,
+                codec,
+            });
+        }
+    }
+
+    Ok(CollectedItem {
+        path: path.into(),
+        data: codec
+            .encode(&bytes, compression.level)
+            .with_context(|| format!("ファイル {path:?} をエンコードできません"))?,
+        uploaded: false,
+        archive,
+        codec,
+    })
+}
+
+/// 異常終了はエラーにせず、終了の仕方を返す（judge 側の設定で状態にする）
+async fn exec_start_sh(dir: &Path, env: &BTreeMap<String, String>) -> Result<ExitStatus> {
+    let mut outfile =
+        File::create(dir.join("start_out.txt")).context("start_out.txt が作成できません")?;
+    let mut errfile =
+        File::create(dir.join("start_err.txt")).context("start_err.txt が作成できません")?;
+
+    let output = tokio::process::Command::new("bash")
+        .current_dir(dir)
+        .envs(env)
+        .arg(dir.join("start.sh"))
         .stdout(Stdio::from(
             outfile
                 .try_clone()
@@ -126,9 +678,11 @@ fn exec_start_sh() -> Result<()> {
                 .try_clone()
                 .context("start_err.txt に接続できません")?,
         ))
+        .kill_on_drop(true)
         .spawn()
         .context("プロセスが起動できません")?
         .wait_with_output()
+        .await
         .context("bash の待機中にエラーが発生しました")?;
 
     let out_res = outfile
@@ -139,9 +693,193 @@ fn exec_start_sh() -> Result<()> {
         .write_all(&output.stderr)
         .context("start_err.txt に書き込めません");
 
-    ensure!(output.status.success(), "start.sh が異常終了しました");
-
     out_res
         .and(err_res)
-        .context("start.sh の出力が最後まで取得できませんでした")
+        .context("start.sh の出力が最後まで取得できませんでした")?;
+
+    Ok(output.status.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dual_judge::codec::Codec;
+
+    fn send(path: &str, content: &str) -> SendItem {
+        SendItem {
+            path: path.into(),
+            data: Codec::Deflate.encode(content.as_bytes(), None).unwrap(),
+            hash: None,
+            url: None,
+            archive: false,
+            mode: None,
+            mtime: None,
+            codec: Codec::Deflate,
+        }
+    }
+
+    fn run(script: &str, collect: &str) -> Request {
+        Request {
+            send: vec![send("start.sh", script)],
+            collect: vec![collect.into()],
+            ..Default::default()
+        }
+    }
+
+    fn collected(response: &Response, path: &str) -> String {
+        let item = response
+            .collected
+            .iter()
+            .find(|item| item.path == Path::new(path))
+            .unwrap();
+        String::from_utf8(item.codec.decode(item.data.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn runs_each_request_in_its_own_directory() {
+        let request = || run("pwd > dir.txt; exit 3", "dir.txt");
+        let (a, b) = tokio::join!(
+            serve(request(), Log::default()),
+            serve(request(), Log::default())
+        );
+
+        let (dir_a, dir_b) = (collected(&a, "dir.txt"), collected(&b, "dir.txt"));
+        assert_ne!(dir_a, dir_b);
+        for (response, dir) in [(&a, dir_a), (&b, dir_b)] {
+            assert_eq!(response.error, None);
+            assert_eq!(response.exit_status.as_ref().unwrap().code, Some(3));
+            let dir = Path::new(dir.trim_end());
+            assert!(dir.starts_with(RUN_DIR));
+            // 終わったら消える
+            assert!(!dir.exists());
+        }
+        let mode = fs::metadata(RUN_DIR).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[tokio::test]
+    async fn failures_become_responses() {
+        // 形式の違うリクエストも Lambda のエラーにしない
+        let response = handle(serde_json::json!({ "send": 5 })).await.unwrap();
+        assert!(response
+            .error
+            .unwrap()
+            .contains("リクエストが解釈できません"));
+        assert!(response.message.contains("[IE]"));
+
+        let response = serve(
+            Request {
+                requires: vec!["teleport".into()],
+                ..run("exit 0", "out.txt")
+            },
+            Log::default(),
+        )
+        .await;
+        assert_eq!(response.unsupported, ["teleport"]);
+        assert_eq!(response.exit_status, None);
+
+        // 展開できなければ実行せず、理由を返す
+        let mut broken = run("exit 0", "out.txt");
+        broken.send[0].data = "!".into();
+        let response = serve(broken, Log::default()).await;
+        assert!(response.error.unwrap().contains("start.sh"));
+        assert!(response.message.contains("[IE] 実行が中断されました"));
+
+        // 実行できても、回収できないファイルは飛ばす
+        let response = serve(run("echo hi > out.txt", "none.txt"), Log::default()).await;
+        assert_eq!(response.error, None);
+        assert!(response.collected.is_empty());
+        assert!(response.exit_status.unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn caches_sent_files_by_hash() {
+        // 他の実行のキャッシュと重ならない内容にする
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let script = format!("# {nanos}\necho cached > out.txt\n");
+        let hash = dual_judge::sha256(script.as_bytes());
+        let with_hash = |data: bool| {
+            let mut request = run(&script, "out.txt");
+            request.send[0].hash = Some(hash.clone());
+            if !data {
+                request.send[0].data.clear();
+            }
+            request
+        };
+
+        let response = serve(with_hash(false), Log::default()).await;
+        assert_eq!(response.missing, std::slice::from_ref(&hash));
+        assert_eq!(response.exit_status, None);
+
+        let response = serve(with_hash(true), Log::default()).await;
+        assert_eq!(collected(&response, "out.txt"), "cached\n");
+        assert!(blob_path(&hash).unwrap().is_file());
+
+        // 2回目からはハッシュだけで実行できる
+        let response = serve(with_hash(false), Log::default()).await;
+        assert!(response.missing.is_empty());
+        assert_eq!(collected(&response, "out.txt"), "cached\n");
+        let _ = fs::remove_file(blob_path(&hash).unwrap());
+    }
+
+    #[test]
+    fn blob_path_rejects_other_paths() {
+        assert!(blob_path(&"0".repeat(64)).is_ok());
+        assert!(blob_path("../../etc/passwd").is_err());
+        assert!(blob_path(&"A".repeat(64)).is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_log_lines_and_collected_files() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let response = serve(run("echo hi > out.txt", "out.txt"), Log::streaming(tx)).await;
+        // 送信済みのものは応答に残らない
+        assert!(response.message.is_empty());
+        assert!(response.collected.is_empty());
+        assert!(response.exit_status.unwrap().success());
+
+        let mut lines = vec![];
+        let mut items = vec![];
+        while let Ok(event) = rx.try_recv() {
+            match event {
+                StreamEvent::Log { line } => lines.push(line),
+                StreamEvent::Collected { item } => items.push(item),
+                StreamEvent::Done { .. } => unreachable!(),
+            }
+        }
+        assert!(lines.iter().all(|line| line.ends_with('\n')));
+        assert!(lines.last().unwrap().contains("実行完了"));
+        assert_eq!(items.len(), 1);
+        assert_eq!(
+            items[0].codec.decode(items[0].data.as_bytes()).unwrap(),
+            b"hi\n"
+        );
+    }
+
+    #[test]
+    fn self_test_checks_commands_and_tmp() {
+        assert!(run_version("bash", &["--version"])
+            .unwrap()
+            .contains("bash"));
+        assert!(run_version("bash", &["-c", "command -v no-such-runtime"]).is_err());
+        assert!(run_version("no-such-program", &[]).is_err());
+        assert!(tmp_writable().is_ok());
+    }
+
+    #[tokio::test]
+    async fn probe_returns_version_without_running() {
+        let request = Request {
+            probe: true,
+            ..run("touch ran.txt", "ran.txt")
+        };
+        let response = serve(request, Log::default()).await;
+        assert_eq!(response.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(response.protocol, dual_judge::lambda::PROTOCOL_VERSION);
+        assert!(response.unsupported.is_empty());
+        assert_eq!(response.exit_status, None);
+        assert!(response.collected.is_empty());
+    }
 }
@@ -1,15 +1,24 @@
 use std::{
-    env,
-    fs::{self, OpenOptions},
-    io::Write as _,
-    path::PathBuf,
+    env, fs,
+    path::{Path, PathBuf},
     process,
 };
 
 use console::Style;
 use getopts::{Matches, Options};
 
-use dual_judge::{config::Config, console_styles::ConsoleStyles, lambda, local, now};
+use dual_judge::{
+    batch,
+    cancel::Cancel,
+    compare,
+    config::{Config, SweepConfig},
+    console_styles::ConsoleStyles,
+    lambda, local, now, report, scorer,
+    submission::{self, Provenance, Rescore, Submission},
+    submission_state::SubmissionStateSingle::AC,
+    summary::{CaseSummary, Comparison, FinalSummary},
+    sweep::{self, Ranking, SweepStrategy},
+};
 
 #[tokio::main]
 async fn main() {
@@ -32,49 +41,579 @@ async fn main() {
         }
     };
 
+    match opt.free.first().map(String::as_str) {
+        None | Some("run") => run(&opt, &cs).await,
+        Some("resume") => resume(&opt, &cs).await,
+        Some("show") => show(&opt, &cs),
+        Some("rescore") => rescore(&opt, &cs).await,
+        Some("version") => version(&opt, &cs).await,
+        Some("fetch") => fetch(&opt, &cs).await,
+        Some(command) => panic!("不明なコマンドです: {command}"),
+    }
+}
+
+async fn run(opt: &Matches, cs: &ConsoleStyles) {
     println!("[CLI][{}] judge_config.yaml を読込・更新", now());
-    let config = Config::load_and_rotate_id("./judge_config.yaml")
+    let mut config = Config::load_and_rotate_id("./judge_config.yaml")
         .expect("judge_config.yaml を読込・更新できません");
 
-    println!("[CLI][{}]  結果フォルダの作成", now());
-    let subm_dir_name = format!("results/s_{:0>4}", config.subm_id);
-    fs::create_dir_all(&subm_dir_name)
-        .expect(&format!("フォルダが作成できません: {subm_dir_name}"));
-
     println!("[CLI][{}] テストケースを決定", now());
-    let casefiles = get_casefiles(&opt, &config, &cs);
+    let mut casefiles = get_casefiles(opt, &config, cs);
+
+    // 指定がなくても種をスナップショットに残して、同じ組み合わせとケースの順を再現できるようにする
+    let seed = match &mut config.sweep {
+        Some(sweep) => {
+            let seed = sweep::seed(sweep);
+            sweep.seed = Some(seed);
+            seed
+        }
+        None => 0,
+    };
+    let submissions = submission::submissions(&config, seed).expect("提出が作成できません");
+    if let Some(sweep) = &config.sweep {
+        let mut prints = format!("パラメータの組み合わせ {} 通り", submissions.len());
+        if sweep.strategy != SweepStrategy::Grid {
+            prints += &format!("（乱数の種 {seed}）");
+        }
+        println!("{}", cs.dim.apply_to(prints));
+        if sweep.strategy == SweepStrategy::Halving {
+            if opt.opt_present("async") {
+                panic!("halving は --async と一緒に使えません");
+            }
+            // 少ないケースで比べるラウンドで、ケースが偏らないように
+            sweep::shuffle(&mut casefiles, seed);
+        }
+    }
 
     println!("[CLI][{}] 結果フォルダを作成", now());
-    let subm_dir = PathBuf::from(format!("results/s_{:0>4}", config.subm_id));
-    fs::create_dir_all(&subm_dir).expect("結果フォルダを作成できません");
-
-    let final_summary = if opt.opt_present("local") {
-        println!("[CLI][{}] ローカルで実行", now());
-        local::run_all(&casefiles, &subm_dir, &config, &cs).await
-    } else if opt.opt_present("lambda") {
-        println!("[CLI][{}] AWS Lambda で実行", now());
-        lambda::run_all(&casefiles, &subm_dir, &config, &cs).await
-    } else {
-        panic!("--lambda / --local を1つ指定してください")
+    for submission in &submissions {
+        let (subm_dir, config) = (&submission.subm_dir, &submission.config);
+        fs::create_dir_all(subm_dir).expect("結果フォルダを作成できません");
+        submission::save_snapshot(subm_dir, config, &casefiles)
+            .expect("設定のスナップショットが保存できません");
+    }
+
+    if opt.opt_present("async") {
+        println!("[CLI][{}] AWS Lambda に非同期で投入", now());
+        // 同期の実行と同じく、ビルドしてから来歴を残して投入する
+        if let Some(commandline) = &submissions[0].config.lambda.pre {
+            println!("{}", cs.cyan.apply_to("=> pre コマンドの実行"));
+            if let Err(e) = dual_judge::run_command(commandline) {
+                println!("pre の実行に失敗しました: {e:?}");
+            }
+        };
+        let cancel = Cancel::listen_ctrl_c(cs);
+        for submission in &submissions {
+            let (subm_dir, config) = (&submission.subm_dir, &submission.config);
+            submission.save_provenance("lambda", &config.lambda.send, &casefiles);
+            let batch = batch::submit_all(&casefiles, subm_dir, config, cs, &cancel)
+                .await
+                .expect("ケースを投入できません");
+            println!(
+                "{} ケースを投入しました。結果は judge fetch {} で回収できます",
+                batch.cases.len(),
+                config.subm_id
+            );
+        }
+        return;
+    }
+
+    let cancel = Cancel::listen_ctrl_c(cs);
+    if let Some(sweep) = &config.sweep {
+        let final_summaries = run_sweep(
+            opt,
+            sweep,
+            config.maximize,
+            &casefiles,
+            &submissions,
+            &cancel,
+            cs,
+        )
+        .await;
+        for (submission, final_summary) in submissions.iter().zip(&final_summaries) {
+            let final_summary =
+                save_summary(&submission.subm_dir, final_summary, &submission.config);
+            if let Err(e) = report::write_report(&submission.subm_dir, &final_summary, None) {
+                println!("レポートの作成に失敗しました: {e:?}");
+            }
+        }
+        println!("{}", cs.cyan.apply_to("=> パラメータの順位"));
+        print!(
+            "{}",
+            Ranking {
+                submissions: &submissions,
+                summaries: &final_summaries,
+                maximize: config.maximize,
+            }
+        );
+        return;
+    }
+
+    let final_summaries = execute(opt, &casefiles, &submissions, &cancel, cs).await;
+    for (i, (submission, final_summary)) in submissions.iter().zip(&final_summaries).enumerate() {
+        if let Some(label) = &submission.label {
+            println!(
+                "{}",
+                cs.cyan.apply_to(format!(
+                    "=> 解答 {label}（提出@{}）",
+                    submission.config.subm_id
+                ))
+            );
+        }
+        // 2つ目以降の解答のレポートは、最初の解答と比べる
+        let baseline = (i > 0).then(|| &final_summaries[0]);
+        finish(
+            opt,
+            &submission.subm_dir,
+            final_summary,
+            &submission.config,
+            baseline,
+            cs,
+        );
+    }
+
+    if submissions.len() > 1 {
+        let labels: Vec<_> = submissions
+            .iter()
+            .filter_map(|submission| submission.label.clone())
+            .collect();
+        println!("{}", cs.cyan.apply_to("=> 解答の比較"));
+        print!(
+            "{}",
+            Comparison {
+                labels: &labels,
+                summaries: &final_summaries,
+                maximize: config.maximize,
+            }
+        );
+    }
+}
+
+/// halving ならラウンドごとに成績の良い組み合わせだけを残し、次のケースを実行する。
+/// pre / post や Lambda の準備はラウンドをまたいで1回だけ行う
+async fn run_sweep(
+    opt: &Matches,
+    sweep: &SweepConfig,
+    maximize: bool,
+    casefiles: &[PathBuf],
+    submissions: &[Submission],
+    cancel: &Cancel,
+    cs: &ConsoleStyles,
+) -> Vec<FinalSummary> {
+    let session = Session::start(opt, casefiles, submissions, cancel, cs).await;
+    let mut alive: Vec<_> = (0..submissions.len()).collect();
+    let mut done = 0;
+
+    let rounds = sweep::rounds(sweep, submissions.len(), casefiles.len());
+    for (round, &(keep, cases)) in rounds.iter().enumerate() {
+        let summaries = session.summaries();
+        let alive_summaries: Vec<_> = alive.iter().map(|&i| &summaries[i]).collect();
+        alive = sweep::rank(&alive_summaries, maximize)
+            .into_iter()
+            .take(keep)
+            .map(|j| alive[j])
+            .collect();
+        if cases <= done {
+            continue;
+        }
+
+        if rounds.len() > 1 {
+            println!(
+                "{}",
+                cs.cyan.apply_to(format!(
+                    "=> ラウンド {}/{}: {keep} 通り × {} ケース",
+                    round + 1,
+                    rounds.len(),
+                    cases - done
+                ))
+            );
+        }
+        session.run(&casefiles[done..cases], &alive).await;
+        done = cases;
+
+        if cancel.is_cancelled() {
+            break;
+        }
+    }
+
+    // 途中で外した組み合わせは残りのケースを実行していないので、 judge resume で続けられるようにする
+    let mut summaries = session.finish();
+    for (i, summary) in summaries.iter_mut().enumerate() {
+        if !alive.contains(&i) {
+            summary.incomplete = true;
+        }
+    }
+    summaries
+}
+
+async fn resume(opt: &Matches, cs: &ConsoleStyles) {
+    let subm_id: u32 = opt
+        .free
+        .get(1)
+        .and_then(|id| id.parse().ok())
+        .expect("再開する提出IDを指定してください: judge resume <subm_id>");
+    let subm_dir = submission::subm_dir(subm_id);
+
+    println!("[CLI][{}] 提出@{subm_id} の設定を読込", now());
+    let (config, casefiles) =
+        submission::load_snapshot(&subm_dir).expect("提出の設定が読み込めません");
+
+    println!("[CLI][{}] 完了済みのケースを確認", now());
+    let (mut done, rest) = split_finished(subm_id, &subm_dir, casefiles);
+    println!(
+        "{}",
+        cs.dim.apply_to(format!(
+            "完了済み {} / 残り {} ファイル",
+            done.count,
+            rest.len()
+        ))
+    );
+
+    let submission = Submission {
+        label: None,
+        subm_dir: subm_dir.clone(),
+        config: config.clone(),
+    };
+    let cancel = Cancel::listen_ctrl_c(cs);
+    let summary = execute(opt, &rest, &[submission], &cancel, cs)
+        .await
+        .remove(0);
+    done.extend(&summary);
+
+    finish(opt, &subm_dir, &done, &config, None, cs);
+}
+
+/// 結果が保存されたケースを集計し、残りのケースファイルを返す
+fn split_finished(
+    subm_id: u32,
+    subm_dir: &Path,
+    casefiles: Vec<PathBuf>,
+) -> (FinalSummary, Vec<PathBuf>) {
+    let mut done = FinalSummary::zero(subm_id);
+    let mut rest = vec![];
+    for casefile in casefiles {
+        let casename = submission::case_name(&casefile);
+        match CaseSummary::load_result(&submission::case_dir(subm_dir, &casename)) {
+            Ok(case) => done.next_case(&case),
+            Err(_) => rest.push(casefile),
+        }
+    }
+    (done, rest)
+}
+
+async fn rescore(opt: &Matches, cs: &ConsoleStyles) {
+    let subm_ids = parse_subm_ids(&opt.free[1..]).unwrap_or_else(|e| {
+        panic!("採点し直す提出IDを指定してください: judge rescore <subm_id|from-to>...: {e}")
+    });
+
+    println!("[CLI][{}] judge_config.yaml を読込", now());
+    let config = Config::load("./judge_config.yaml").expect("judge_config.yaml を読み込めません");
+
+    for subm_id in subm_ids {
+        let subm_dir = submission::subm_dir(subm_id);
+        if !subm_dir.is_dir() {
+            println!(
+                "{}",
+                cs.dim.apply_to(format!("提出@{subm_id} はありません"))
+            );
+            continue;
+        }
+        println!("{}", cs.cyan.apply_to(format!("=> 提出@{subm_id}")));
+        rescore_one(opt, subm_id, &subm_dir, &config, cs).await;
+    }
+}
+
+/// 保存された出力を今の parse_result と scorer で読み直す
+async fn rescore_one(
+    opt: &Matches,
+    subm_id: u32,
+    subm_dir: &Path,
+    config: &Config,
+    cs: &ConsoleStyles,
+) {
+    // 古い提出にはスナップショットがないので、そのときは結果フォルダだけから読む。
+    // スナップショットは実行時の記録なので書き換えず、採点に使った設定は別に残す
+    let snapshot = submission::load_snapshot(subm_dir).ok();
+    if let Err(e) = Rescore::new(config).save(subm_dir) {
+        println!("採点し直しの設定が保存できません: {e:?}");
+    }
+
+    let casedirs = match submission::case_dirs(subm_dir) {
+        Ok(casedirs) => casedirs,
+        Err(e) => {
+            println!("{}", cs.red.apply_to(format!("{e:#}")));
+            return;
+        }
     };
 
+    println!("[CLI][{}] 保存された出力を採点", now());
+    let mut final_summary = FinalSummary::zero(subm_id);
+    for (casename, casedir) in &casedirs {
+        let stored = CaseSummary::load_result(casedir).ok();
+        if snapshot.is_some() && stored.is_none() {
+            // 中断されて結果が保存されなかったケース
+            final_summary.incomplete = true;
+            continue;
+        }
+        let (exit_status, billing) = stored
+            .map(|stored| (stored.exit_status, stored.billing))
+            .unwrap_or_default();
+
+        let casefile = match &snapshot {
+            Some((_, casefiles)) => casefiles
+                .iter()
+                .find(|casefile| &submission::case_name(casefile) == casename)
+                .cloned(),
+            None => None,
+        }
+        .unwrap_or_else(|| config.case_dir.join(casename));
+
+        let case = CaseSummary::parse_case(casename, casedir, config, Ok(exit_status));
+        let case = compare::apply(case, &casefile, casedir, config.compare.as_ref()).await;
+        let mut case = scorer::apply(case, &casefile, casedir, config.scorer.as_ref()).await;
+        case.billing = billing;
+        if let Err(e) = case.save_result(casedir) {
+            println!("ケースの結果が保存できません: {e:?}");
+        }
+
+        if case.state == AC as u32 {
+            println!("{case}");
+        } else {
+            println!("{}", cs.red.apply_to(&case));
+        }
+        final_summary.next_case(&case);
+    }
+
+    finish(opt, subm_dir, &final_summary, config, None, cs);
+}
+
+/// 12 や 10-15 のような指定を提出IDの一覧にする
+fn parse_subm_ids(args: &[String]) -> Result<Vec<u32>, String> {
+    if args.is_empty() {
+        return Err("提出IDがありません".into());
+    }
+
+    let mut ids = vec![];
+    for arg in args {
+        let parse = |id: &str| {
+            id.parse::<u32>()
+                .map_err(|_| format!("提出IDが誤っています: {arg}"))
+        };
+        match arg.split_once('-') {
+            Some((from, to)) => {
+                let (from, to) = (parse(from)?, parse(to)?);
+                if from > to {
+                    return Err(format!("範囲の始めが終わりより大きいです: {arg}"));
+                }
+                ids.extend(from..=to);
+            }
+            None => ids.push(parse(arg)?),
+        }
+    }
+    Ok(ids)
+}
+
+fn show(opt: &Matches, cs: &ConsoleStyles) {
+    let subm_id: u32 = opt
+        .free
+        .get(1)
+        .and_then(|id| id.parse().ok())
+        .expect("表示する提出IDを指定してください: judge show <subm_id>");
+    let subm_dir = submission::subm_dir(subm_id);
+
+    println!("{}", cs.cyan.apply_to(format!("=> 提出@{subm_id} の来歴")));
+    match Provenance::load(&subm_dir) {
+        Ok(provenance) => print!("{provenance}"),
+        Err(e) => println!("来歴がありません: {e:?}"),
+    }
+    println!(
+        "設定: {}",
+        subm_dir.join(submission::CONFIG_SNAPSHOT).display()
+    );
+    println!();
+
+    println!("{}", cs.cyan.apply_to("=> 要約"));
+    match fs::read_to_string(subm_dir.join("summary.txt")) {
+        Ok(summary) => print!("{summary}"),
+        Err(e) => println!("要約がありません: {e:?}"),
+    }
+}
+
+async fn fetch(opt: &Matches, cs: &ConsoleStyles) {
+    let subm_id: u32 = opt
+        .free
+        .get(1)
+        .and_then(|id| id.parse().ok())
+        .expect("回収する提出IDを指定してください: judge fetch <subm_id>");
+    let subm_dir = submission::subm_dir(subm_id);
+
+    println!("[CLI][{}] 提出@{subm_id} の設定を読込", now());
+    let (config, _) = submission::load_snapshot(&subm_dir).expect("提出の設定が読み込めません");
+
+    println!("[CLI][{}] 結果を回収", now());
+    let cancel = Cancel::listen_ctrl_c(cs);
+    let final_summary = batch::fetch(&subm_dir, &config, cs, &cancel, opt.opt_present("wait"))
+        .await
+        .expect("結果を回収できません");
+
+    if final_summary.incomplete {
+        println!();
+        print!("{final_summary}");
+        println!(
+            "{}",
+            cs.dim.apply_to(format!(
+                "残りは judge fetch {subm_id} で回収できます（--wait で揃うまで待つ）"
+            ))
+        );
+        return;
+    }
+
+    if !cancel.is_cancelled() {
+        if let Some(commandline) = &config.lambda.post {
+            println!("{}", cs.cyan.apply_to("=> post コマンドの実行"));
+            if let Err(e) = dual_judge::run_command(commandline) {
+                println!("post の実行に失敗しました: {e:?}");
+            }
+        };
+    }
+    finish(opt, &subm_dir, &final_summary, &config, None, cs);
+}
+
+async fn version(opt: &Matches, cs: &ConsoleStyles) {
+    println!(
+        "judge {}（プロトコル {}）",
+        env!("CARGO_PKG_VERSION"),
+        lambda::PROTOCOL_VERSION
+    );
+    if !opt.opt_present("lambda") {
+        return;
+    }
+
+    let config = Config::load("./judge_config.yaml").expect("judge_config.yaml を読み込めません");
+    let sdk_config = lambda::load_sdk_config(&config.lambda.client)
+        .await
+        .expect("AWS のクライアントが作成できません");
+    let client = aws_sdk_lambda::Client::new(&sdk_config);
+    match lambda::probe(&client, &config).await {
+        Ok(remote) => {
+            println!("bootstrap {}", remote.remote_version());
+            println!(
+                "{}",
+                cs.dim
+                    .apply_to(format!("対応機能: {}", remote.capabilities.join(", ")))
+            );
+        }
+        Err(e) => println!("bootstrap のバージョンが確認できません: {e:#}"),
+    }
+}
+
+async fn execute(
+    opt: &Matches,
+    casefiles: &[PathBuf],
+    submissions: &[Submission],
+    cancel: &Cancel,
+    cs: &ConsoleStyles,
+) -> Vec<FinalSummary> {
+    let session = Session::start(opt, casefiles, submissions, cancel, cs).await;
+    let targets: Vec<_> = (0..submissions.len()).collect();
+    session.run(casefiles, &targets).await;
+    session.finish()
+}
+
+/// --local / --lambda での実行。 halving ではラウンドをまたいで使う
+enum Session {
+    Local(local::Session),
+    Lambda(lambda::Session),
+}
+
+impl Session {
+    async fn start(
+        opt: &Matches,
+        casefiles: &[PathBuf],
+        submissions: &[Submission],
+        cancel: &Cancel,
+        cs: &ConsoleStyles,
+    ) -> Self {
+        if opt.opt_present("local") {
+            println!("[CLI][{}] ローカルで実行", now());
+            Session::Local(local::Session::start(casefiles, submissions, cs, cancel))
+        } else if opt.opt_present("lambda") {
+            println!("[CLI][{}] AWS Lambda で実行", now());
+            // 1ケースも実行していないので、要約を残さずに終わる
+            let session = lambda::Session::start(casefiles, submissions, cs, cancel)
+                .await
+                .unwrap_or_else(|e| {
+                    println!("{}", cs.red.apply_to(format!("{e:#}")));
+                    process::exit(1)
+                });
+            Session::Lambda(session)
+        } else {
+            panic!("--lambda / --local を1つ指定してください")
+        }
+    }
+
+    async fn run(&self, casefiles: &[PathBuf], targets: &[usize]) {
+        match self {
+            Session::Local(session) => session.run(casefiles, targets).await,
+            Session::Lambda(session) => session.run(casefiles, targets).await,
+        }
+    }
+
+    fn summaries(&self) -> Vec<FinalSummary> {
+        match self {
+            Session::Local(session) => session.summaries(),
+            Session::Lambda(session) => session.summaries(),
+        }
+    }
+
+    fn finish(self) -> Vec<FinalSummary> {
+        match self {
+            Session::Local(session) => session.finish(),
+            Session::Lambda(session) => session.finish(),
+        }
+    }
+}
+
+fn finish(
+    opt: &Matches,
+    subm_dir: &Path,
+    final_summary: &FinalSummary,
+    config: &Config,
+    baseline: Option<&FinalSummary>,
+    cs: &ConsoleStyles,
+) {
     println!("[CLI][{}] 要約の表示・保存", now());
     println!();
+    let final_summary = &save_summary(subm_dir, final_summary, config);
     print!("{}", final_summary);
-    let mut summary_file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&subm_dir.join("summary.txt"))
-        .unwrap();
-    summary_file
-        .write_all(&final_summary.to_string().as_bytes())
-        .unwrap();
+
+    println!("[CLI][{}] レポートの作成", now());
+    let compare = opt.opt_str("compare").map(|id| {
+        let id: u32 = id.parse().expect("--compare には提出IDを指定してください");
+        FinalSummary::load_dir(id, &submission::subm_dir(id), config)
+            .expect("比較対象の提出が読み込めません")
+    });
+    match report::write_report(subm_dir, final_summary, compare.as_ref().or(baseline)) {
+        Ok(path) => println!("{}", cs.dim.apply_to(path.display())),
+        Err(e) => println!("レポートの作成に失敗しました: {e:?}"),
+    }
+}
+
+/// summary.txt を書き直す。再開や採点し直しでも要約は1つだけにする。費用の見積もりを付けた要約を返す
+fn save_summary(subm_dir: &Path, final_summary: &FinalSummary, config: &Config) -> FinalSummary {
+    let mut final_summary = final_summary.clone();
+    if final_summary.billing.is_some() {
+        final_summary.price = Some(config.lambda.price.clone());
+    }
+    fs::write(subm_dir.join("summary.txt"), final_summary.to_string())
+        .expect("summary.txt が保存できません");
+    final_summary
 }
 
 fn get_casefiles(opt: &Matches, config: &Config, cs: &ConsoleStyles) -> Vec<PathBuf> {
     let caseopts = opt.opt_strs("case");
 
-    let casefiles: Vec<PathBuf> = if caseopts.len() > 0 {
+    let casefiles: Vec<PathBuf> = if !caseopts.is_empty() {
         casefiles_selected(caseopts, config)
     } else {
         casefiles_auto(config)
@@ -114,17 +653,21 @@ fn casefiles_selected(caseopts: Vec<String>, config: &Config) -> Vec<PathBuf> {
 fn casefiles_auto(config: &Config) -> Vec<PathBuf> {
     PathBuf::from(&config.case_dir)
         .read_dir()
-        .expect(
-            format!(
+        .unwrap_or_else(|_| {
+            panic!(
                 "テストケースフォルダが読み込めません: {}",
                 config.case_dir.display()
             )
-            .as_str(),
-        )
+        })
         .filter_map(|entry| {
             let path = entry.ok()?.path();
             path.is_file().then_some(path)
         })
+        // 期待する出力はテストケースにしない
+        .filter(|path| match &config.compare {
+            Some(compare) => path.extension() != Some(compare.expected_ext.as_ref()),
+            None => true,
+        })
         .collect()
 }
 
@@ -133,12 +676,21 @@ fn parse_options(args: &[String]) -> Matches {
     opts.optflag("", "local", "このコンピュータで実行");
     opts.optflag("", "lambda", "AWS Lambda で実行");
     opts.optmulti("c", "case", "テストケースをファイル名で指定", "<name>");
+    opts.optopt("", "compare", "レポートで比較する提出ID", "<subm_id>");
+    opts.optflag(
+        "",
+        "async",
+        "--lambda で、結果を待たずに投入だけする（judge fetch で回収）",
+    );
+    opts.optflag("", "wait", "fetch で、すべての結果が揃うまで待つ");
     opts.optflag("", "no-color", "出力に色を付けない");
     opts.optflag("h", "help", "このヘルプを表示");
 
-    let usage = opts.usage(&format!("Usage: judge [Options]"));
+    let usage = opts.usage(
+        "Usage: judge [run] [Options]\n       judge resume <subm_id> [Options]\n       judge fetch <subm_id> [--wait]\n       judge show <subm_id>\n       judge rescore <subm_id|from-to>...\n       judge version [--lambda]",
+    );
 
-    let opt_match = opts.parse(&args[..]).unwrap_or_else(|e| {
+    let opt_match = opts.parse(args).unwrap_or_else(|e| {
         println!("{usage}");
         eprintln!("{e:?}");
         panic!("オプションが誤っています");
@@ -149,10 +701,81 @@ fn parse_options(args: &[String]) -> Matches {
         process::exit(0);
     }
 
-    if opt_match.opt_count("lambda") + opt_match.opt_count("local") != 1 {
+    let needs_mode = !matches!(
+        opt_match.free.first().map(String::as_str),
+        Some("show" | "version" | "fetch" | "rescore")
+    );
+    if needs_mode && opt_match.opt_count("lambda") + opt_match.opt_count("local") != 1 {
         println!("{usage}");
         panic!("--lambda / --local を1つ指定してください");
     }
+    if opt_match.opt_present("async") && !opt_match.opt_present("lambda") {
+        println!("{usage}");
+        panic!("--async は --lambda と一緒に指定してください");
+    }
 
     opt_match
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dual_judge::{submission_state::SubmissionStateSingle::WA, summary};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_ids_and_ranges() {
+        assert_eq!(parse_subm_ids(&args(&["12"])), Ok(vec![12]));
+        assert_eq!(
+            parse_subm_ids(&args(&["3", "10-12", "7-7"])),
+            Ok(vec![3, 10, 11, 12, 7])
+        );
+    }
+
+    #[test]
+    fn resumes_cases_without_results() {
+        let temp = tempfile::tempdir().unwrap();
+        let subm_dir = temp.path();
+        let casefiles: Vec<PathBuf> = ["in/0000.txt", "in/0001.txt", "in/0002.txt", "in/0003.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+
+        let finished = CaseSummary {
+            score: 10.,
+            ..CaseSummary::zero("0000", AC as u32)
+        };
+        for (casename, case) in [
+            ("0000", &finished),
+            ("0002", &CaseSummary::zero("0002", WA as u32)),
+        ] {
+            let casedir = submission::case_dir(subm_dir, casename);
+            fs::create_dir_all(&casedir).unwrap();
+            case.save_result(&casedir).unwrap();
+        }
+        // 実行中に止まったケースはフォルダだけ残る。壊れた結果もやり直す
+        fs::create_dir_all(submission::case_dir(subm_dir, "0001")).unwrap();
+        let broken = submission::case_dir(subm_dir, "0003");
+        fs::create_dir_all(&broken).unwrap();
+        fs::write(broken.join(summary::CASE_RESULT), "{").unwrap();
+
+        let (done, rest) = split_finished(7, subm_dir, casefiles.clone());
+        assert_eq!(rest, [casefiles[1].clone(), casefiles[3].clone()]);
+        assert_eq!(done.subm_id, 7);
+        assert_eq!(done.count, 2);
+        assert_eq!(done.ac_count, 1);
+        assert_eq!(done.state, WA as u32);
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        assert!(parse_subm_ids(&[]).is_err());
+        assert!(parse_subm_ids(&args(&["15-10"])).is_err());
+        assert!(parse_subm_ids(&args(&["a"])).is_err());
+        assert!(parse_subm_ids(&args(&["10-"])).is_err());
+        assert!(parse_subm_ids(&args(&["-3"])).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::HashSet,
+    process,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::watch;
+
+use crate::console_styles::ConsoleStyles;
+
+static PROCESS_GROUPS: Mutex<Option<HashSet<u32>>> = Mutex::new(None);
+
+#[derive(Debug, Clone)]
+pub struct Cancel {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Cancel {
+    pub fn new() -> Self {
+        Self {
+            tx: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// 1回目の Ctrl-C で中断を通知し、2回目で実行中のプロセスを kill して即座に終了する
+    pub fn listen_ctrl_c(cs: &ConsoleStyles) -> Self {
+        let cancel = Self::new();
+        let (cancel2, cs) = (cancel.clone(), cs.clone());
+
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            println!(
+                "{}",
+                cs.red.apply_to(
+                    "=> 中断します。実行中のケースを停止しています（もう一度 Ctrl-C で強制終了）"
+                )
+            );
+            cancel2.cancel();
+
+            if tokio::signal::ctrl_c().await.is_ok() {
+                println!("{}", cs.red.apply_to("=> 強制終了します"));
+                kill_all_process_groups();
+                process::exit(130);
+            }
+        });
+
+        cancel
+    }
+
+    pub fn cancel(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    pub async fn cancelled(&self) {
+        let mut rx = self.tx.subscribe();
+        let _ = rx.wait_for(|&cancelled| cancelled).await;
+    }
+}
+
+impl Default for Cancel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn register_process_group(pgid: u32) {
+    PROCESS_GROUPS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashSet::new)
+        .insert(pgid);
+}
+
+pub fn unregister_process_group(pgid: u32) {
+    if let Some(groups) = PROCESS_GROUPS.lock().unwrap().as_mut() {
+        groups.remove(&pgid);
+    }
+}
+
+pub fn kill_process_group(pgid: u32) {
+    #[cfg(unix)]
+    unsafe {
+        libc::killpg(pgid as libc::pid_t, libc::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    let _ = pgid;
+}
+
+pub fn kill_all_process_groups() {
+    if let Some(groups) = PROCESS_GROUPS.lock().unwrap().as_ref() {
+        for &pgid in groups {
+            kill_process_group(pgid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn cancel_reaches_every_clone() {
+        let cancel = Cancel::new();
+        let waiter = cancel.clone();
+        let waiting = tokio::spawn(async move { waiter.cancelled().await });
+        assert!(!cancel.is_cancelled());
+
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(5), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        // 中断した後に待ち始めても、すぐに返る
+        tokio::time::timeout(Duration::from_secs(5), cancel.clone().cancelled())
+            .await
+            .unwrap();
+        assert!(cancel.clone().is_cancelled());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kills_process_group() {
+        use std::os::unix::process::CommandExt as _;
+
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        // kill_all_process_groups は並行する他のテストのプロセスも止めるので使わない
+        kill_process_group(child.id());
+        assert!(!child.wait().unwrap().success());
+    }
+}
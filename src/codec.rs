@@ -0,0 +1,159 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::io::Write;
+
+use base64::engine::{general_purpose, Engine as _};
+use flate2::write::{DeflateDecoder, DeflateEncoder};
+use serde::{Deserialize, Serialize};
+
+/// 送受信するファイルの圧縮形式。
+/// 古い bootstrap は deflate しか扱えないので、省略時は deflate とみなす
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    None,
+    #[default]
+    Deflate,
+    Zstd,
+}
+
+/// 設定ファイルで選べる圧縮形式。 auto ならサイズで決める
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodecChoice {
+    None,
+    #[default]
+    Deflate,
+    Zstd,
+    Auto,
+}
+
+/// auto のとき、これより小さいファイルは圧縮しない (バイト)
+pub const AUTO_NONE_BELOW: usize = 1 << 10;
+/// auto のとき、これ以上のファイルは zstd にする (バイト)
+pub const AUTO_ZSTD_FROM: usize = 64 << 10;
+
+impl Codec {
+    pub fn is_deflate(&self) -> bool {
+        *self == Codec::Deflate
+    }
+
+    /// オブジェクトストレージのキーにつける拡張子
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::None => ".raw",
+            Codec::Deflate => "",
+            Codec::Zstd => ".zst",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8], level: Option<i32>) -> Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Deflate => {
+                let level = match level {
+                    Some(level) => flate2::Compression::new(level.clamp(0, 9) as u32),
+                    None => flate2::Compression::default(),
+                };
+                let mut gz = DeflateEncoder::new(vec![], level);
+                gz.write_all(data)?;
+                Ok(gz.finish()?)
+            }
+            Codec::Zstd => Ok(zstd::encode_all(data, level.unwrap_or(0))?),
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Deflate => {
+                let mut gz = DeflateDecoder::new(vec![]);
+                gz.write_all(data)?;
+                Ok(gz.finish()?)
+            }
+            Codec::Zstd => Ok(zstd::decode_all(data)?),
+        }
+    }
+
+    /// 圧縮して base64 にする
+    pub fn encode(&self, data: &[u8], level: Option<i32>) -> Result<String> {
+        Ok(general_purpose::STANDARD_NO_PAD.encode(self.compress(data, level)?))
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.decompress(&general_purpose::STANDARD_NO_PAD.decode(data)?)
+    }
+}
+
+impl CodecChoice {
+    pub fn select(&self, size: usize) -> Codec {
+        match self {
+            CodecChoice::None => Codec::None,
+            CodecChoice::Deflate => Codec::Deflate,
+            CodecChoice::Zstd => Codec::Zstd,
+            CodecChoice::Auto if size < AUTO_NONE_BELOW => Codec::None,
+            CodecChoice::Auto if size < AUTO_ZSTD_FROM => Codec::Deflate,
+            CodecChoice::Auto => Codec::Zstd,
+        }
+    }
+}
+
+/// ファイルごとの圧縮の指定
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Compression {
+    pub codec: CodecChoice,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<i32>,
+}
+
+impl Compression {
+    pub fn select(&self, size: usize) -> Codec {
+        self.codec.select(size)
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Compression::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codecs_round_trip() {
+        let data = b"0 1 2 3\n".repeat(1000);
+        for codec in [Codec::None, Codec::Deflate, Codec::Zstd] {
+            for level in [None, Some(1), Some(19)] {
+                let encoded = codec.encode(&data, level).unwrap();
+                assert_eq!(codec.decode(encoded.as_bytes()).unwrap(), data);
+            }
+        }
+        assert_eq!(Codec::None.compress(&data, None).unwrap(), data);
+        assert!(Codec::Zstd.compress(&data, None).unwrap().len() < data.len());
+        // 別の形式では展開できない
+        let zstd = Codec::Zstd.compress(&data, None).unwrap();
+        assert!(Codec::Deflate.decompress(&zstd).is_err());
+    }
+
+    #[test]
+    fn auto_selects_by_size() {
+        assert_eq!(CodecChoice::Auto.select(0), Codec::None);
+        assert_eq!(CodecChoice::Auto.select(AUTO_NONE_BELOW - 1), Codec::None);
+        assert_eq!(CodecChoice::Auto.select(AUTO_NONE_BELOW), Codec::Deflate);
+        assert_eq!(CodecChoice::Auto.select(AUTO_ZSTD_FROM), Codec::Zstd);
+        assert_eq!(CodecChoice::Zstd.select(0), Codec::Zstd);
+        assert_eq!(CodecChoice::None.select(AUTO_ZSTD_FROM), Codec::None);
+    }
+
+    #[test]
+    fn reads_codec_from_config() {
+        let compression: Compression = serde_yaml::from_str("codec: zstd").unwrap();
+        assert_eq!(compression.level, None);
+        assert_eq!(compression.select(0), Codec::Zstd);
+        let codec: Codec = serde_yaml::from_str("zstd").unwrap();
+        assert_eq!(codec.extension(), ".zst");
+        assert!(Codec::default().is_deflate());
+        assert!(Compression::default().is_default());
+    }
+}
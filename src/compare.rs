@@ -0,0 +1,338 @@
+//! 解答の出力を期待する出力と比べる。テスターのない、答えが決まっている問題向け
+
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::{
+    config::CompareConfig, exit_status::ExitStatus, submission_state::SubmissionStateSingle::*,
+    summary::CaseSummary,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompareMode {
+    /// 行ごとに完全一致（改行コードと最後の改行の有無は無視）
+    Exact,
+    /// 空白と改行の違いを無視して、トークンごとに比べる
+    #[default]
+    Whitespace,
+    /// whitespace に加えて、数値は abs_tol / rel_tol の誤差を許す
+    Float,
+}
+
+/// 比較の結果を書く行の先頭。比べ直すときはこの行を書き換え、状態やスコアの読み取りには使わない
+pub const MESSAGE_PREFIX: &str = "[CLI] [比較] ";
+/// 差分の表示で切り詰める長さ (文字)
+const SHOW_CHARS: usize = 80;
+
+pub fn expected_path(casefile: &Path, config: &CompareConfig) -> PathBuf {
+    casefile.with_extension(&config.expected_ext)
+}
+
+/// 設定があれば比べて、ケースの結果に反映する。違っていたら最初の差分を message.txt に書く。
+/// 実行の時点で AC でなければ（TLE や RE で出力がないなど）比べない
+pub async fn apply(
+    summary: CaseSummary,
+    casefile: &Path,
+    casedir: &Path,
+    compare: Option<&CompareConfig>,
+) -> CaseSummary {
+    let Some(compare) = compare else {
+        return summary;
+    };
+
+    if summary.state != AC as u32 {
+        record(casedir, None);
+        return summary;
+    }
+
+    // judge rescore で直っても状態が残らないように、ここでは [WA] などの印を付けない
+    let (state, line) = match check(compare, casefile, casedir).await {
+        Ok(None) => {
+            record(casedir, None);
+            return summary;
+        }
+        Ok(Some(diff)) => (WA, format!("出力が一致しません: {diff}")),
+        Err(e) => (IE, format!("出力を比較できません: {e:#}")),
+    };
+    record(casedir, Some(&line));
+
+    CaseSummary {
+        state: summary.state | state as u32,
+        ..summary
+    }
+}
+
+/// message.txt の前の比較の行を line で置き換える。 judge rescore で比べ直しても行が増えない
+fn record(casedir: &Path, line: Option<&str>) {
+    let path = casedir.join("message.txt");
+    let text = fs::read_to_string(&path).unwrap_or_default();
+    let mut kept = without_result(&text);
+    if let Some(line) = line {
+        kept.push_str(MESSAGE_PREFIX);
+        kept.push_str(line);
+        kept.push('\n');
+    }
+    if kept == text {
+        return;
+    }
+    if let Err(e) = fs::write(&path, kept) {
+        println!("message.txt に書き込めません: {e:?}");
+    }
+}
+
+/// 比較の結果の行を除いた text
+pub fn without_result(text: &str) -> String {
+    text.split_inclusive('\n')
+        .filter(|line| !line.starts_with(MESSAGE_PREFIX))
+        .collect()
+}
+
+/// 一致すれば None 、違えば最初の差分
+async fn check(config: &CompareConfig, casefile: &Path, casedir: &Path) -> Result<Option<String>> {
+    let output = casedir.join(&config.output);
+    let expected = expected_path(casefile, config);
+    ensure!(expected.is_file(), "期待する出力 {expected:?} がありません");
+    // 正常に終了して出力しなかったのは解答の誤り
+    if !output.is_file() {
+        return Ok(Some(format!(
+            "解答の出力 {} がありません",
+            config.output.display()
+        )));
+    }
+
+    if let Some(checker) = &config.checker {
+        return run_checker(checker, casefile, &output, &expected).await;
+    }
+
+    let output = fs::read(&output).with_context(|| format!("{output:?} が読み取れません"))?;
+    let expected = fs::read(&expected).with_context(|| format!("{expected:?} が読み取れません"))?;
+    let (output, expected) = (
+        String::from_utf8_lossy(&output),
+        String::from_utf8_lossy(&expected),
+    );
+
+    Ok(match config.mode {
+        CompareMode::Exact => diff_lines(&expected, &output),
+        CompareMode::Whitespace => diff_tokens(&expected, &output, |e, o| e == o),
+        CompareMode::Float => diff_tokens(&expected, &output, |e, o| {
+            e == o || float_eq(e, o, config.abs_tol, config.rel_tol)
+        }),
+    })
+}
+
+async fn run_checker(
+    checker: &str,
+    casefile: &Path,
+    output: &Path,
+    expected: &Path,
+) -> Result<Option<String>> {
+    let result = Command::new("bash")
+        .arg("-c")
+        .arg(format!("{checker} \"$@\""))
+        .arg("checker")
+        .args([casefile, output, expected])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("チェッカーが起動できません")?;
+
+    let status = ExitStatus::from(result.status);
+    match status.code {
+        Some(0) => Ok(None),
+        Some(1) => {
+            // チェッカーの出力の最初の行を差分として扱う
+            let text = [result.stdout, result.stderr].concat();
+            let text = String::from_utf8_lossy(&text);
+            let first = text.lines().find(|line| !line.trim().is_empty());
+            Ok(Some(shorten(
+                first.unwrap_or("チェッカーが WA を返しました"),
+            )))
+        }
+        _ => bail!("チェッカーが異常終了しました: {status}"),
+    }
+}
+
+fn diff_lines(expected: &str, output: &str) -> Option<String> {
+    let expected: Vec<_> = expected.lines().collect();
+    let output: Vec<_> = output.lines().collect();
+
+    let i = (0..expected.len().max(output.len())).find(|&i| expected.get(i) != output.get(i))?;
+    Some(format!(
+        "{} 行目: 期待 {}, 出力 {}",
+        i + 1,
+        show(expected.get(i).copied()),
+        show(output.get(i).copied())
+    ))
+}
+
+fn diff_tokens(expected: &str, output: &str, eq: impl Fn(&str, &str) -> bool) -> Option<String> {
+    let expected = tokens(expected);
+    let output = tokens(output);
+
+    for (i, (e, o)) in expected.iter().zip(&output).enumerate() {
+        if !eq(e.1, o.1) {
+            return Some(format!(
+                "{} 番目のトークン（出力の {} 行目）: 期待 {}, 出力 {}",
+                i + 1,
+                o.0,
+                show(Some(e.1)),
+                show(Some(o.1))
+            ));
+        }
+    }
+
+    (expected.len() != output.len()).then(|| {
+        format!(
+            "トークン数が違います: 期待 {}, 出力 {}",
+            expected.len(),
+            output.len()
+        )
+    })
+}
+
+/// (行番号, トークン)
+fn tokens(text: &str) -> Vec<(usize, &str)> {
+    text.lines()
+        .enumerate()
+        .flat_map(|(i, line)| line.split_whitespace().map(move |token| (i + 1, token)))
+        .collect()
+}
+
+fn float_eq(expected: &str, output: &str, abs_tol: f64, rel_tol: f64) -> bool {
+    let (Ok(e), Ok(o)) = (expected.parse::<f64>(), output.parse::<f64>()) else {
+        return false;
+    };
+    let d = (e - o).abs();
+    d <= abs_tol || d <= rel_tol * e.abs()
+}
+
+fn show(token: Option<&str>) -> String {
+    match token {
+        Some(token) => format!("`{}`", shorten(token)),
+        None => "(なし)".into(),
+    }
+}
+
+fn shorten(text: &str) -> String {
+    if text.chars().count() <= SHOW_CHARS {
+        text.into()
+    } else {
+        format!("{}...", text.chars().take(SHOW_CHARS).collect::<String>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::submission_state::SubmissionStateSingle;
+
+    fn float(e: &str, o: &str, abs_tol: f64, rel_tol: f64) -> bool {
+        e == o || float_eq(e, o, abs_tol, rel_tol)
+    }
+
+    #[test]
+    fn whitespace_ignores_layout() {
+        assert_eq!(diff_tokens("1 2\n3\n", "1\n2 3", |e, o| e == o), None);
+        let diff = diff_tokens("1 2\n3\n", "1 2\n4\n", |e, o| e == o).unwrap();
+        assert!(
+            diff.starts_with("3 番目のトークン（出力の 2 行目）"),
+            "{diff}"
+        );
+    }
+
+    #[test]
+    fn extra_or_missing_tokens_are_wrong() {
+        let eq = |e: &str, o: &str| e == o;
+        assert_eq!(
+            diff_tokens("1 2", "1 2 3", eq).as_deref(),
+            Some("トークン数が違います: 期待 2, 出力 3")
+        );
+        assert_eq!(
+            diff_tokens("1 2", "1", eq).as_deref(),
+            Some("トークン数が違います: 期待 2, 出力 1")
+        );
+    }
+
+    #[test]
+    fn float_tolerances() {
+        assert!(float("1.0", "1.0000001", 1e-6, 0.));
+        assert!(!float("1.0", "1.01", 1e-6, 0.));
+        assert!(float("1000000", "1000001", 0., 1e-6));
+        // 期待値が 0 なら rel_tol では誤差を許さない
+        assert!(!float("0", "1e-12", 0., 1e-6));
+        assert!(float("0", "1e-12", 1e-9, 1e-6));
+    }
+
+    #[test]
+    fn float_rejects_nan_and_words() {
+        assert!(!float("1.0", "nan", 1e-6, 1e-6));
+        assert!(!float("nan", "1.0", 1e-6, 1e-6));
+        // 文字列として同じなら一致
+        assert!(float("nan", "nan", 0., 0.));
+        assert!(!float("yes", "Yes", 1e-6, 1e-6));
+    }
+
+    async fn apply_in(dir: &Path, state: SubmissionStateSingle) -> CaseSummary {
+        let config: CompareConfig = serde_yaml::from_str("output: out.txt").unwrap();
+        let summary = CaseSummary::zero("0000", state as u32);
+        apply(summary, &dir.join("0000.in"), dir, Some(&config)).await
+    }
+
+    #[tokio::test]
+    async fn rewrites_diff_line_in_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        fs::write(dir.join("0000.out"), "1\n").unwrap();
+        fs::write(dir.join("message.txt"), "[CLI] 実行完了\n").unwrap();
+
+        fs::write(dir.join("out.txt"), "2\n").unwrap();
+        assert_eq!(apply_in(dir, AC).await.state, WA as u32);
+        assert_eq!(apply_in(dir, AC).await.state, WA as u32);
+        let message = fs::read_to_string(dir.join("message.txt")).unwrap();
+        assert_eq!(message.matches(MESSAGE_PREFIX).count(), 1, "{message}");
+        assert_eq!(without_result(&message), "[CLI] 実行完了\n");
+
+        fs::write(dir.join("out.txt"), "1\n").unwrap();
+        assert_eq!(apply_in(dir, AC).await.state, AC as u32);
+        let message = fs::read_to_string(dir.join("message.txt")).unwrap();
+        assert_eq!(message, "[CLI] 実行完了\n");
+    }
+
+    #[tokio::test]
+    async fn missing_output_is_wrong_unless_already_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        fs::write(dir.join("0000.out"), "1\n").unwrap();
+
+        assert_eq!(apply_in(dir, AC).await.state, WA as u32);
+        // TLE や RE はそのまま。 IE にしない
+        assert_eq!(apply_in(dir, TLE).await.state, TLE as u32);
+        assert_eq!(apply_in(dir, RE).await.state, RE as u32);
+
+        fs::remove_file(dir.join("0000.out")).unwrap();
+        assert_eq!(apply_in(dir, AC).await.state, IE as u32);
+    }
+
+    #[test]
+    fn exact_compares_lines() {
+        assert_eq!(diff_lines("a b\r\nc\n", "a b\nc"), None);
+        assert_eq!(
+            diff_lines("a b\nc\n", "a  b\nc\n").as_deref(),
+            Some("1 行目: 期待 `a b`, 出力 `a  b`")
+        );
+        assert_eq!(
+            diff_lines("a\n", "a\nb\n").as_deref(),
+            Some("2 行目: 期待 (なし), 出力 `b`")
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Notify;
+
+use crate::summary::Concurrency;
+
+/// 待ち時間の移動平均の重み
+const LATENCY_ALPHA: f64 = 0.2;
+/// スロットリングされたときに同時実行数に掛ける係数
+const THROTTLE_DECREASE: f64 = 0.5;
+/// 待ち時間が延びたときに同時実行数に掛ける係数
+const LATENCY_DECREASE: f64 = 0.8;
+/// 続けて減らさないようにする最短の間隔
+const MIN_COOLDOWN: Duration = Duration::from_secs(1);
+
+/// AIMD で同時実行数を調整するセマフォ。
+/// 成功するたびに少しずつ上限を上げ、スロットリングや待ち時間の延びがあれば上限を下げる。
+/// max が None なら initial で固定する
+#[derive(Debug)]
+pub struct AdaptiveLimit {
+    state: Mutex<State>,
+    notify: Notify,
+    max: Option<usize>,
+    latency_factor: f64,
+}
+
+#[derive(Debug)]
+struct State {
+    limit: f64,
+    in_flight: usize,
+    peak: usize,
+    throttled: usize,
+    /// 待ち時間の移動平均 (秒)
+    latency: Option<f64>,
+    last_decrease: Option<Instant>,
+    /// 実行中の数の時間積分。平均の計算に使う
+    area: f64,
+    started: Option<Instant>,
+    last_update: Option<Instant>,
+}
+
+pub struct Permit {
+    limit: Arc<AdaptiveLimit>,
+}
+
+impl AdaptiveLimit {
+    pub fn new(initial: usize, max: Option<usize>, latency_factor: f64) -> Arc<Self> {
+        let initial = match max {
+            Some(max) => initial.clamp(1, max.max(1)),
+            None => initial.max(1),
+        };
+        Arc::new(Self {
+            state: Mutex::new(State {
+                limit: initial as f64,
+                in_flight: 0,
+                peak: 0,
+                throttled: 0,
+                latency: None,
+                last_decrease: None,
+                area: 0.,
+                started: None,
+                last_update: None,
+            }),
+            notify: Notify::new(),
+            max,
+            latency_factor,
+        })
+    }
+
+    pub async fn acquire(self: &Arc<Self>) -> Permit {
+        loop {
+            // 確認より先に作っておけば、その間の解放も取りこぼさない
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.in_flight < state.limit.floor() as usize {
+                    state.advance();
+                    state.in_flight += 1;
+                    state.peak = state.peak.max(state.in_flight);
+                    return Permit {
+                        limit: self.clone(),
+                    };
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// 成功した呼び出しを伝える。 overhead は応答時間から課金時間を引いた待ち時間。
+    /// 解答の実行時間はケースによって違うので含めない。分からなければ None で、そのときは下げない
+    pub fn on_success(&self, overhead: Option<Duration>) {
+        let Some(max) = self.max else { return };
+        let mut state = self.state.lock().unwrap();
+        let overhead = overhead.map(|overhead| overhead.as_secs_f64());
+
+        match (state.latency, overhead) {
+            (Some(average), Some(overhead)) if overhead > average * self.latency_factor => {
+                state.decrease(LATENCY_DECREASE);
+            }
+            _ => {
+                state.limit = (state.limit + 1. / state.limit).min(max as f64);
+            }
+        }
+        if let Some(overhead) = overhead {
+            state.latency = Some(match state.latency {
+                Some(average) => average + (overhead - average) * LATENCY_ALPHA,
+                None => overhead,
+            });
+        }
+        drop(state);
+        self.notify.notify_waiters();
+    }
+
+    /// スロットリングされたことを伝える
+    pub fn on_throttle(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.throttled += 1;
+        if self.max.is_some() {
+            state.decrease(THROTTLE_DECREASE);
+        }
+    }
+
+    pub fn stats(&self) -> Concurrency {
+        let mut state = self.state.lock().unwrap();
+        state.advance();
+        let elapsed = match (state.started, state.last_update) {
+            (Some(started), Some(last)) => (last - started).as_secs_f64(),
+            _ => 0.,
+        };
+        Concurrency {
+            mean: if elapsed > 0. {
+                state.area / elapsed
+            } else {
+                state.peak as f64
+            },
+            peak: state.peak,
+            limit: state.limit.floor() as usize,
+            throttled: state.throttled,
+        }
+    }
+}
+
+impl State {
+    /// 実行中の数の時間積分を進める
+    fn advance(&mut self) {
+        let now = Instant::now();
+        if let Some(last) = self.last_update {
+            self.area += self.in_flight as f64 * (now - last).as_secs_f64();
+        }
+        self.started.get_or_insert(now);
+        self.last_update = Some(now);
+    }
+
+    /// 待ち時間1回分（最短 MIN_COOLDOWN）が経つまでは続けて減らさない
+    fn decrease(&mut self, factor: f64) {
+        let cooldown = self.latency.map_or(MIN_COOLDOWN, |secs| {
+            Duration::from_secs_f64(secs).max(MIN_COOLDOWN)
+        });
+        if self
+            .last_decrease
+            .is_some_and(|last| last.elapsed() < cooldown)
+        {
+            return;
+        }
+        self.limit = (self.limit * factor).max(1.);
+        self.last_decrease = Some(Instant::now());
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.limit.state.lock().unwrap();
+        state.advance();
+        state.in_flight -= 1;
+        drop(state);
+        self.limit.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(limit: &Arc<AdaptiveLimit>) -> usize {
+        limit.stats().limit
+    }
+
+    #[test]
+    fn fixed_without_max() {
+        let fixed = AdaptiveLimit::new(3, None, 3.);
+        fixed.on_success(Some(Duration::from_millis(10)));
+        fixed.on_throttle();
+        assert_eq!(limit(&fixed), 3);
+        assert_eq!(fixed.stats().throttled, 1);
+    }
+
+    #[test]
+    fn initial_is_clamped_to_max() {
+        assert_eq!(limit(&AdaptiveLimit::new(10, Some(4), 3.)), 4);
+        assert_eq!(limit(&AdaptiveLimit::new(0, Some(4), 3.)), 1);
+    }
+
+    #[test]
+    fn increases_up_to_max() {
+        let adaptive = AdaptiveLimit::new(1, Some(2), 3.);
+        adaptive.on_success(None);
+        assert_eq!(limit(&adaptive), 2);
+        for _ in 0..10 {
+            adaptive.on_success(None);
+        }
+        assert_eq!(limit(&adaptive), 2);
+    }
+
+    #[test]
+    fn throttle_halves_once_per_cooldown() {
+        let adaptive = AdaptiveLimit::new(8, Some(8), 3.);
+        adaptive.on_throttle();
+        assert_eq!(limit(&adaptive), 4);
+        // 同じころに投げた呼び出しのスロットリングでは、続けて下げない
+        adaptive.on_throttle();
+        assert_eq!(limit(&adaptive), 4);
+        assert_eq!(adaptive.stats().throttled, 2);
+    }
+
+    #[test]
+    fn only_overhead_spikes_decrease() {
+        let adaptive = AdaptiveLimit::new(8, Some(8), 3.);
+        adaptive.on_success(Some(Duration::from_millis(100)));
+        assert_eq!(limit(&adaptive), 8);
+        // 課金時間が分からない（解答の実行時間が長いだけかもしれない）ときは下げない
+        adaptive.on_success(None);
+        assert_eq!(limit(&adaptive), 8);
+        adaptive.on_success(Some(Duration::from_millis(1000)));
+        assert_eq!(limit(&adaptive), 6);
+    }
+
+    #[tokio::test]
+    async fn acquire_waits_for_release() {
+        let adaptive = AdaptiveLimit::new(1, None, 3.);
+        let permit = adaptive.acquire().await;
+        let waiting = tokio::time::timeout(Duration::from_millis(20), adaptive.acquire()).await;
+        assert!(waiting.is_err());
+
+        drop(permit);
+        let permit = tokio::time::timeout(Duration::from_secs(1), adaptive.acquire()).await;
+        assert!(permit.is_ok());
+        assert_eq!(adaptive.stats().peak, 1);
+    }
+}
@@ -1,10 +1,18 @@
 #[allow(unused_imports)]
 use anyhow::{anyhow, bail, ensure, Context, Result};
-use std::{fs, path::PathBuf};
+use std::{collections::BTreeMap, fs, path::PathBuf};
 
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    codec::{CodecChoice, Compression},
+    compare::CompareMode,
+    exit_status::ExitStatus,
+    submission_state::{SubmissionState, SubmissionStateSingle},
+    sweep::{SweepParam, SweepStrategy},
+};
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Config {
     pub subm_id: u32,
@@ -13,23 +21,189 @@ pub struct Config {
     pub local: LocalConfig,
     pub lambda: LambdaConfig,
     pub parse_result: ParseResultConfig,
+    #[serde(default)]
+    pub interactive: Option<InteractiveConfig>,
+    #[serde(default)]
+    pub scorer: Option<ScorerConfig>,
+    #[serde(default)]
+    pub compare: Option<CompareConfig>,
+    /// 複数の解答を同じケースで実行して比べる。解答ごとに提出IDを1つ使う
+    #[serde(default)]
+    pub solutions: Vec<SolutionConfig>,
+    /// スコアが大きいほど良いなら true 。解答の比較と sweep の順位で使う
+    #[serde(default = "Config::default_maximize")]
+    pub maximize: bool,
+    /// start.sh や対話型のコマンドに渡す環境変数（ローカルと Lambda 共通）
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub sweep: Option<SweepConfig>,
+}
+
+/// パラメータを変えながら同じケースを実行する。組み合わせごとに提出IDを1つ使い、パラメータは env に加える
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SweepConfig {
+    #[serde(default)]
+    pub strategy: SweepStrategy,
+    /// 試す組み合わせの数の上限
+    pub budget: usize,
+    /// random / halving の乱数の種。 null なら実行ごとに変わる
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// halving で、ラウンドごとに組み合わせを 1 / eta に減らし、ケースを eta 倍に増やす
+    #[serde(default = "SweepConfig::default_eta")]
+    pub eta: usize,
+    /// 環境変数の名前と、その値の選び方
+    pub params: BTreeMap<String, SweepParam>,
+}
+
+impl SweepConfig {
+    fn default_eta() -> usize {
+        2
+    }
+
+    /// 組み合わせの数。乱数によらないので、先に提出IDを確保できる
+    pub fn count(&self) -> usize {
+        match self.strategy {
+            SweepStrategy::Grid => self
+                .params
+                .values()
+                .map(|param| match param {
+                    SweepParam::Values { values } => values.len(),
+                    SweepParam::Range { .. } => 1,
+                })
+                .fold(1usize, |n, m| n.saturating_mul(m))
+                .min(self.budget),
+            SweepStrategy::Random | SweepStrategy::Halving => self.budget,
+        }
+    }
+}
+
+/// 比べる解答の1つ
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SolutionConfig {
+    pub name: String,
+    /// local / lambda の send に、 to が同じものは置き換え、なければ追加する
+    #[serde(default)]
+    pub send: Vec<FileTransferConfig>,
+}
+
+/// 対話型の問題。指定すると start.sh の代わりに、
+/// judge と solution を互いの標準入出力でつないで実行する（ローカルと Lambda 共通）
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct InteractiveConfig {
+    /// 実行ディレクトリで bash -c に渡すコマンド
+    pub judge: String,
+    pub solution: String,
+    /// 解答だけにかける制限時間。超えたら解答を停止する
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_limit_secs: Option<f64>,
+    /// やりとりを記録するファイル（実行ディレクトリからの相対パス）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transcript: Option<PathBuf>,
+}
+
+/// 回収した出力を手元で採点するコマンド。 judge rescore でやり直せる
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ScorerConfig {
+    /// bash -c に渡すコマンド。引数に入力、出力、（あれば）期待する出力のパスが続く
+    pub command: String,
+    /// 解答の出力（ケースの結果フォルダからの相対パス）
+    pub output: PathBuf,
+    /// 期待する出力のパス。 $casename はケース名に置き換える
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+}
+
+/// 解答の出力を、テストケースと対になる期待する出力と比べて AC / WA を決める
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct CompareConfig {
+    /// 解答の出力（ケースの結果フォルダからの相対パス）
+    pub output: PathBuf,
+    /// 期待する出力の拡張子。 case_dir のこの拡張子のファイルはテストケースにしない
+    #[serde(default = "CompareConfig::default_expected_ext")]
+    pub expected_ext: String,
+    #[serde(default)]
+    pub mode: CompareMode,
+    /// float のときの誤差。どちらかに収まれば一致とみなす
+    #[serde(default = "CompareConfig::default_tolerance")]
+    pub abs_tol: f64,
+    #[serde(default = "CompareConfig::default_tolerance")]
+    pub rel_tol: f64,
+    /// 指定すると比較の代わりに実行する。引数は scorer と同じで、終了コード 0 なら AC 、 1 なら WA
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checker: Option<String>,
+}
+
+impl CompareConfig {
+    fn default_expected_ext() -> String {
+        "out".into()
+    }
+
+    fn default_tolerance() -> f64 {
+        1e-9
+    }
 }
 
 impl Config {
+    fn default_maximize() -> bool {
+        true
+    }
+
+    /// 提出IDを進めずに読む
+    pub fn load(path: &str) -> Result<Config> {
+        let yaml = fs::read_to_string(path)?;
+        serde_yaml::from_str(&yaml).context("設定ファイルが誤っています")
+    }
+
+    /// solutions や sweep があれば、作る提出の数だけ提出IDを進める
     pub fn load_and_rotate_id(path: &str) -> Result<Config> {
         let yaml = fs::read_to_string(path)?;
 
         let config: Config = serde_yaml::from_str(&yaml).context("設定ファイルが誤っています")?;
+        let count = config.submission_count();
 
-        let next_yaml = Regex::new(r"subm_id: ?[0-9]+").unwrap().replace(
-            &yaml,
-            format!("subm_id: {}", (config.subm_id + 1).to_string()),
-        );
+        let next_yaml = Regex::new(r"subm_id: ?[0-9]+")
+            .unwrap()
+            .replace(&yaml, format!("subm_id: {}", (config.subm_id + count)));
 
         fs::write(path, next_yaml.as_bytes()).context("設定ファイルを上書きできません")?;
 
         Ok(config)
     }
+
+    /// 1回の実行で作る提出の数
+    pub fn submission_count(&self) -> u32 {
+        match &self.sweep {
+            Some(sweep) => sweep.count().max(1) as u32,
+            None => self.solutions.len().max(1) as u32,
+        }
+    }
+
+    /// パラメータの組み合わせごとの設定。 env に加える。
+    /// sweep は乱数の種ごとスナップショットに残して、どの探索の組み合わせか再現できるようにする
+    pub fn for_params(&self, params: &BTreeMap<String, String>, subm_id: u32) -> Config {
+        let mut config = self.clone();
+        config.subm_id = subm_id;
+        config.env.extend(params.clone());
+        config
+    }
+
+    /// 解答ごとの設定。送信ファイルを上書きして、 solutions は空にする
+    pub fn for_solution(&self, solution: &SolutionConfig, subm_id: u32) -> Config {
+        let mut config = self.clone();
+        config.subm_id = subm_id;
+        config.solutions = vec![];
+        for send in [&mut config.local.send, &mut config.lambda.send] {
+            for item in &solution.send {
+                match send.iter_mut().find(|t| t.to == item.to) {
+                    Some(t) => *t = item.clone(),
+                    None => send.push(item.clone()),
+                }
+            }
+        }
+        config
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -49,12 +223,168 @@ pub struct LambdaConfig {
     pub send: Vec<FileTransferConfig>,
     pub collect: Vec<FileTransferConfig>,
     pub post: Option<String>,
+    #[serde(default)]
+    pub artifact_store: Option<ArtifactStoreConfig>,
+    /// 送受信するファイルの圧縮形式。 deflate 以外は新しい bootstrap でないと扱えない
+    #[serde(default)]
+    pub codec: CodecChoice,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec_level: Option<i32>,
+    #[serde(default)]
+    pub client: LambdaClientConfig,
+    /// 応答ストリーミングを有効にした関数 URL 。指定するとログと回収ファイルが届いた順に保存される
+    #[serde(default)]
+    pub function_url: Option<String>,
+    #[serde(default)]
+    pub batch: BatchConfig,
+    /// 指定すると parallel から始めて、スロットリングされない範囲でこの数まで同時実行数を上げる
+    #[serde(default)]
+    pub max_parallel: Option<usize>,
+    /// 応答時間が移動平均のこの倍数を超えたら、同時実行数を下げる
+    #[serde(default = "LambdaConfig::default_latency_factor")]
+    pub latency_factor: f64,
+    #[serde(default)]
+    pub price: LambdaPriceConfig,
+}
+
+/// 費用の見積もりに使う料金 (USD)。既定は us-east-1 の x86
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct LambdaPriceConfig {
+    #[serde(default = "LambdaPriceConfig::default_per_gb_second")]
+    pub per_gb_second: f64,
+    #[serde(default = "LambdaPriceConfig::default_per_request")]
+    pub per_request: f64,
+}
+
+impl Default for LambdaPriceConfig {
+    fn default() -> Self {
+        LambdaPriceConfig {
+            per_gb_second: LambdaPriceConfig::default_per_gb_second(),
+            per_request: LambdaPriceConfig::default_per_request(),
+        }
+    }
+}
+
+impl LambdaPriceConfig {
+    fn default_per_gb_second() -> f64 {
+        0.0000166667
+    }
+
+    fn default_per_request() -> f64 {
+        0.0000002
+    }
+}
+
+/// --async で実行するときの設定
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct BatchConfig {
+    /// 指定すると Lambda の代わりにこのディレクトリに投入する（`bootstrap --worker <dir>` で処理）
+    #[serde(default)]
+    pub queue_dir: Option<PathBuf>,
+    /// judge fetch --wait で結果を確認する間隔 (秒)
+    #[serde(default = "BatchConfig::default_poll_secs")]
+    pub poll_secs: u64,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            queue_dir: None,
+            poll_secs: BatchConfig::default_poll_secs(),
+        }
+    }
+}
+
+impl BatchConfig {
+    fn default_poll_secs() -> u64 {
+        10
+    }
+}
+
+/// AWS SDK のクライアントの設定。省略した項目は環境変数や ~/.aws/config に従う
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+pub struct LambdaClientConfig {
+    #[serde(default)]
+    pub region: Option<String>,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub connect_timeout_secs: Option<u64>,
+    /// 1ケースの実行時間より長くする
+    #[serde(default)]
+    pub read_timeout_secs: Option<u64>,
+    /// 接続できなかったときに試す回数（最初の1回を含む）。
+    /// Invoke はタイムアウトでは再試行せず、スロットリングは別に再試行する
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+}
+
+impl LambdaConfig {
+    fn default_latency_factor() -> f64 {
+        3.
+    }
+
+    /// ファイルごとの指定がなければ全体の指定を使う
+    pub fn compression(&self, transfer_config: &FileTransferConfig) -> Compression {
+        Compression {
+            codec: transfer_config.codec.unwrap_or(self.codec),
+            level: transfer_config.level.or(self.codec_level),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ArtifactStoreConfig {
+    pub bucket: String,
+    /// MinIO などを使うときに指定（パス形式の URL になる）
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub region: Option<String>,
+    #[serde(default = "ArtifactStoreConfig::default_prefix")]
+    pub prefix: String,
+    /// これより大きいファイルだけオブジェクトストレージを経由する (バイト)
+    #[serde(default = "ArtifactStoreConfig::default_inline_limit")]
+    pub inline_limit: u64,
+    #[serde(default = "ArtifactStoreConfig::default_expires_secs")]
+    pub expires_secs: u64,
+}
+
+impl ArtifactStoreConfig {
+    fn default_prefix() -> String {
+        "dual-judge/".into()
+    }
+
+    fn default_inline_limit() -> u64 {
+        1 << 20
+    }
+
+    fn default_expires_secs() -> u64 {
+        6 * 60 * 60
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct FileTransferConfig {
     pub from: PathBuf,
     pub to: PathBuf,
+    /// "755" のような8進数。指定すると元の権限の代わりに使う
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    /// Lambda との送受信での圧縮形式と圧縮レベル
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<CodecChoice>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<i32>,
+}
+
+impl FileTransferConfig {
+    pub fn mode(&self) -> Result<Option<u32>> {
+        self.mode
+            .as_deref()
+            .map(crate::transfer::parse_mode)
+            .transpose()
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -75,4 +405,133 @@ pub struct ParseResultConfig {
     pub wa_regex: String,
     pub tle_regex: String,
     pub mle_regex: String,
+    #[serde(default)]
+    pub exit_status: ExitStatusConfig,
+}
+
+/// start.sh の終了の仕方から状態を決める。正常終了なら何もしない
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ExitStatusConfig {
+    #[serde(default)]
+    pub codes: BTreeMap<i32, SubmissionStateSingle>,
+    /// SIGSEGV のような名前で指定する
+    #[serde(default)]
+    pub signals: BTreeMap<String, SubmissionStateSingle>,
+    /// codes にも signals にもない異常終了
+    #[serde(default = "ExitStatusConfig::default_other")]
+    pub other: SubmissionStateSingle,
+}
+
+impl Default for ExitStatusConfig {
+    fn default() -> Self {
+        ExitStatusConfig {
+            // start.sh の中のコマンドがシグナルで終了すると、 bash は 128 + 番号で終わる。
+            // 134 (SIGABRT) 、 136 (SIGFPE) 、 139 (SIGSEGV) は解答の実行時エラー。
+            // 137 (SIGKILL) はこちらが制限時間で止めたとき以外は OOM killer によるものとして MLE にする。
+            // 124 は timeout コマンドで打ち切られたとき。
+            // 126 と 127 は start.sh のコマンドが実行できない・見つからないときで、解答のせいではない
+            codes: BTreeMap::from([
+                (1, SubmissionStateSingle::WA),
+                (124, SubmissionStateSingle::TLE),
+                (126, SubmissionStateSingle::IE),
+                (127, SubmissionStateSingle::IE),
+                (134, SubmissionStateSingle::RE),
+                (136, SubmissionStateSingle::RE),
+                (137, SubmissionStateSingle::MLE),
+                (139, SubmissionStateSingle::RE),
+            ]),
+            signals: BTreeMap::from([
+                ("SIGSEGV".into(), SubmissionStateSingle::RE),
+                ("SIGABRT".into(), SubmissionStateSingle::RE),
+            ]),
+            other: ExitStatusConfig::default_other(),
+        }
+    }
+}
+
+impl ExitStatusConfig {
+    fn default_other() -> SubmissionStateSingle {
+        SubmissionStateSingle::RE
+    }
+
+    /// 制限時間で止めたときは、終了の仕方によらず TLE
+    pub fn state(&self, status: &ExitStatus) -> SubmissionState {
+        if status.timed_out {
+            return SubmissionStateSingle::TLE as SubmissionState;
+        }
+        if status.success() {
+            return SubmissionStateSingle::AC as SubmissionState;
+        }
+        let mapped = match (&status.signal, status.code) {
+            (Some(signal), _) => self.signals.get(signal),
+            (None, Some(code)) => self.codes.get(&code),
+            (None, None) => None,
+        };
+        *mapped.unwrap_or(&self.other) as SubmissionState
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use SubmissionStateSingle::*;
+
+    fn exited(code: i32) -> ExitStatus {
+        ExitStatus {
+            code: Some(code),
+            signal: None,
+            timed_out: false,
+            time: None,
+        }
+    }
+
+    fn killed(signal: &str) -> ExitStatus {
+        ExitStatus {
+            code: None,
+            signal: Some(signal.into()),
+            timed_out: false,
+            time: None,
+        }
+    }
+
+    #[test]
+    fn default_exit_status_states() {
+        let config = ExitStatusConfig::default();
+        assert_eq!(config.state(&exited(0)), AC as u32);
+        assert_eq!(config.state(&exited(1)), WA as u32);
+        assert_eq!(config.state(&exited(124)), TLE as u32);
+        assert_eq!(config.state(&exited(127)), IE as u32);
+        assert_eq!(config.state(&killed("SIGSEGV")), RE as u32);
+        // bash 経由でシグナルで終わった解答は 128 + 番号になる
+        assert_eq!(config.state(&exited(134)), RE as u32);
+        assert_eq!(config.state(&exited(136)), RE as u32);
+        assert_eq!(config.state(&exited(139)), RE as u32);
+        // OOM でも SIGKILL になるので、制限時間で止めたとき以外は TLE にしない
+        assert_eq!(config.state(&exited(137)), MLE as u32);
+        assert_eq!(config.state(&killed("SIGKILL")), RE as u32);
+        let timed_out = ExitStatus {
+            timed_out: true,
+            ..killed("SIGKILL")
+        };
+        assert_eq!(config.state(&timed_out), TLE as u32);
+    }
+
+    #[test]
+    fn configured_exit_status_states() {
+        let config: ExitStatusConfig =
+            serde_yaml::from_str("{ codes: { 3: WA }, signals: { SIGTERM: TLE }, other: IE }")
+                .unwrap();
+        assert_eq!(config.state(&exited(3)), WA as u32);
+        assert_eq!(config.state(&exited(1)), IE as u32);
+        assert_eq!(config.state(&killed("SIGTERM")), TLE as u32);
+        assert_eq!(
+            config.state(&ExitStatus {
+                code: None,
+                signal: None,
+                timed_out: false,
+                time: None,
+            }),
+            IE as u32
+        );
+    }
 }
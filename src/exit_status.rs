@@ -0,0 +1,80 @@
+//! start.sh の終了コードとシグナル。ローカルと bootstrap で同じように記録する
+
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExitStatus {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<i32>,
+    /// SIGSEGV のような名前。シグナルで終了したときだけ入る
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
+    /// 制限時間を超えたので止めたとき。シグナルだけでは OOM などと区別できない
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub timed_out: bool,
+    /// judge が測った実行時間 (秒)。対話型の解答のように、出力から時間を読めないときに使う
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<f64>,
+}
+
+impl ExitStatus {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+impl From<std::process::ExitStatus> for ExitStatus {
+    fn from(status: std::process::ExitStatus) -> Self {
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(&status).map(signal_name);
+        #[cfg(not(unix))]
+        let signal = None;
+
+        ExitStatus {
+            code: status.code(),
+            signal,
+            timed_out: false,
+            time: None,
+        }
+    }
+}
+
+impl Display for ExitStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.code, &self.signal) {
+            (_, Some(signal)) => write!(f, "シグナル {signal}")?,
+            (Some(code), None) => write!(f, "終了コード {code}")?,
+            (None, None) => write!(f, "不明")?,
+        }
+        if self.timed_out {
+            write!(f, "（制限時間で停止）")?;
+        }
+        Ok(())
+    }
+}
+
+/// 番号は OS によって違うので、記録した側で名前にしておく
+#[cfg(unix)]
+fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        _ => return format!("SIG{signal}"),
+    };
+    name.into()
+}
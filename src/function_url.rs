@@ -0,0 +1,209 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::time::SystemTime;
+
+use aws_config::SdkConfig;
+use aws_credential_types::provider::SharedCredentialsProvider;
+use aws_sigv4::{
+    http_request::{sign, SignableBody, SignableRequest, SigningSettings},
+    sign::v4,
+};
+use hyper::{body::HttpBody as _, Body, Method, Request, StatusCode};
+
+use crate::artifact_store::{self, CachedCredentials, HttpClient};
+
+/// 応答ストリーミングを有効にした Lambda 関数 URL 。
+/// SDK の Invoke では応答がまとめて返るので、途中経過を受け取るにはこちらを使う
+#[derive(Debug, Clone)]
+pub struct FunctionUrl {
+    url: String,
+    region: String,
+    /// 長い実行の途中で期限が切れないように、署名のたびに取り直す
+    credentials: CachedCredentials,
+    http: HttpClient,
+}
+
+impl FunctionUrl {
+    pub async fn new(url: &str, sdk_config: &SdkConfig) -> Result<Self> {
+        let region = sdk_config
+            .region()
+            .context("AWS のリージョンが設定されていません")?
+            .to_string();
+
+        let provider = sdk_config
+            .credentials_provider()
+            .context("AWS の認証情報が設定されていません")?;
+
+        let function_url = Self::with_provider(url, region, provider);
+        function_url.credentials.identity().await?;
+        Ok(function_url)
+    }
+
+    pub fn with_provider(url: &str, region: String, provider: SharedCredentialsProvider) -> Self {
+        Self {
+            url: url.into(),
+            region,
+            credentials: CachedCredentials::new(provider),
+            http: artifact_store::http_client(),
+        }
+    }
+
+    /// 署名して POST し、応答の本体を読み終える前に返す
+    pub async fn post(&self, body: Vec<u8>) -> Result<BodyLines> {
+        let request = self.signed_request(body).await?;
+        let response = self
+            .http
+            .request(request)
+            .await
+            .context("関数 URL に接続できません")?;
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            bail!(TooManyRequests);
+        }
+        if !status.is_success() {
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            bail!(
+                "関数 URL の呼び出しが失敗しました: {status}\n{}",
+                String::from_utf8_lossy(&body)
+            );
+        }
+
+        Ok(BodyLines {
+            body: response.into_body(),
+            buf: vec![],
+        })
+    }
+
+    async fn signed_request(&self, body: Vec<u8>) -> Result<Request<Body>> {
+        let identity = self.credentials.identity().await?;
+        let params = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(&self.region)
+            .name("lambda")
+            .time(SystemTime::now())
+            .settings(SigningSettings::default())
+            .build()
+            .context("署名のパラメータが作れません")?
+            .into();
+
+        let signable = SignableRequest::new(
+            "POST",
+            self.url.as_str(),
+            [("content-type", "application/json")].into_iter(),
+            SignableBody::Bytes(&body),
+        )
+        .context("署名するリクエストが作れません")?;
+
+        let (instructions, _) = sign(signable, &params)
+            .context("リクエストに署名できません")?
+            .into_parts();
+
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.url)
+            .header("content-type", "application/json")
+            .body(Body::from(body))?;
+        instructions.apply_to_request(&mut request);
+        Ok(request)
+    }
+}
+
+/// 関数 URL がスロットリングされた (429)
+#[derive(Debug)]
+pub struct TooManyRequests;
+
+impl std::fmt::Display for TooManyRequests {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "関数 URL がスロットリングされました")
+    }
+}
+
+impl std::error::Error for TooManyRequests {}
+
+/// 応答の本体を1行ずつ読む
+pub struct BodyLines {
+    body: Body,
+    buf: Vec<u8>,
+}
+
+impl BodyLines {
+    /// 本体が終わったら None 。最後の行に改行がなくても返す
+    pub async fn next_line(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(i) = self.buf.iter().position(|&b| b == b'\n') {
+                let rest = self.buf.split_off(i + 1);
+                let mut line = std::mem::replace(&mut self.buf, rest);
+                line.pop();
+                return Ok(Some(line));
+            }
+
+            match self.body.data().await {
+                Some(chunk) => self
+                    .buf
+                    .extend_from_slice(&chunk.context("応答の受信中に切断されました")?),
+                None if self.buf.is_empty() => return Ok(None),
+                None => return Ok(Some(std::mem::take(&mut self.buf))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::artifact_store::tests::counting_provider;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    const URL: &str = "https://example.lambda-url.us-east-1.on.aws/";
+
+    #[tokio::test]
+    async fn signs_with_session_token() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let function_url = FunctionUrl::with_provider(
+            URL,
+            "us-east-1".into(),
+            counting_provider(calls.clone(), Duration::from_secs(3600)),
+        );
+        let request = function_url.signed_request(b"{}".to_vec()).await.unwrap();
+        let authorization = request.headers()["authorization"].to_str().unwrap();
+        assert!(authorization.contains("/us-east-1/lambda/aws4_request"));
+        assert_eq!(request.headers()["x-amz-security-token"], "TOKEN");
+
+        function_url.signed_request(b"{}".to_vec()).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn refreshes_expiring_credentials() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let function_url = FunctionUrl::with_provider(
+            URL,
+            "us-east-1".into(),
+            counting_provider(calls.clone(), Duration::from_secs(60)),
+        );
+        function_url.signed_request(vec![]).await.unwrap();
+        function_url.signed_request(vec![]).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn body_lines_keep_last_line_without_newline() {
+        let mut lines = BodyLines {
+            body: Body::from("a\nbc\nd"),
+            buf: vec![],
+        };
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some(&b"a"[..]));
+        assert_eq!(
+            lines.next_line().await.unwrap().as_deref(),
+            Some(&b"bc"[..])
+        );
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some(&b"d"[..]));
+        assert_eq!(lines.next_line().await.unwrap(), None);
+    }
+}
@@ -0,0 +1,297 @@
+//! 対話型の問題。ジャッジと解答を互いのパイプでつないで実行する。ローカルと bootstrap で共通
+
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::File,
+    io::Write as _,
+    path::Path,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _},
+    process::{Child, Command},
+};
+
+use crate::{
+    cancel::{self, Cancel},
+    config::InteractiveConfig,
+    exit_status::ExitStatus,
+};
+
+/// ジャッジの標準エラー出力
+pub const JUDGE_ERR: &str = "judge_err.txt";
+/// 解答の標準エラー出力
+pub const SOLUTION_ERR: &str = "err.txt";
+/// 解答が終わった後、ジャッジの終了を待つ時間。ジャッジが止まらなくても待ち続けない
+const JUDGE_GRACE: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub solution: ExitStatus,
+    pub judge: ExitStatus,
+    /// 解答の起動から終了まで
+    pub elapsed: Duration,
+    pub timed_out: bool,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "解答 {} ({} ms), ジャッジ {}",
+            self.solution,
+            self.elapsed.as_millis(),
+            self.judge
+        )
+    }
+}
+
+impl Outcome {
+    /// 状態の正規表現にマッチさせる行。問題なければ None
+    pub fn verdict(&self) -> Option<&'static str> {
+        if self.timed_out {
+            Some("[TLE] 解答が制限時間を超えたので停止しました")
+        } else if self.judge.timed_out {
+            Some("[IE] 解答の終了後もジャッジが終わらないので停止しました")
+        } else if !self.judge.success() {
+            Some("[WA] ジャッジが異常終了しました")
+        } else {
+            None
+        }
+    }
+}
+
+/// dir でジャッジと解答を起動し、両方が終わるまで待つ。制限時間は解答にだけかけ、
+/// ジャッジは解答が終わってから JUDGE_GRACE だけ待つ
+pub async fn run(
+    config: &InteractiveConfig,
+    dir: &Path,
+    env: &BTreeMap<String, String>,
+    cancel: &Cancel,
+) -> Result<Outcome> {
+    let transcript = match &config.transcript {
+        Some(path) => Some(Arc::new(Mutex::new(
+            File::create(dir.join(path)).with_context(|| format!("{path:?} が作成できません"))?,
+        ))),
+        None => None,
+    };
+
+    let mut judge =
+        spawn(&config.judge, dir, env, JUDGE_ERR).context("ジャッジが起動できません")?;
+    let mut solution =
+        spawn(&config.solution, dir, env, SOLUTION_ERR).context("解答が起動できません")?;
+    let start = Instant::now();
+
+    let judge_pgid = judge.id().context("プロセスIDが取得できません")?;
+    let solution_pgid = solution.id().context("プロセスIDが取得できません")?;
+    cancel::register_process_group(judge_pgid);
+    cancel::register_process_group(solution_pgid);
+
+    let relays = [
+        tokio::spawn(relay(
+            judge.stdout.take().unwrap(),
+            solution.stdin.take().unwrap(),
+            transcript.clone(),
+            ">",
+        )),
+        tokio::spawn(relay(
+            solution.stdout.take().unwrap(),
+            judge.stdin.take().unwrap(),
+            transcript,
+            "<",
+        )),
+    ];
+
+    let limit = config.time_limit_secs.map(Duration::from_secs_f64);
+    let solution_wait = async {
+        let waited = match limit {
+            Some(limit) => tokio::time::timeout(limit, solution.wait()).await.ok(),
+            None => Some(solution.wait().await),
+        };
+        match waited {
+            Some(status) => (status, start.elapsed(), false),
+            None => {
+                let elapsed = start.elapsed();
+                cancel::kill_process_group(solution_pgid);
+                (solution.wait().await, elapsed, true)
+            }
+        }
+    };
+
+    let waited = tokio::select! {
+        waited = async {
+            let solution = solution_wait.await;
+            let judge = tokio::time::timeout(JUDGE_GRACE, judge.wait()).await.ok();
+            (solution, judge)
+        } => Some(waited),
+        _ = cancel.cancelled() => None,
+    };
+
+    // 残った子孫のプロセスがパイプを握っていると中継が終わらないので、まとめて止める
+    cancel::kill_process_group(judge_pgid);
+    cancel::kill_process_group(solution_pgid);
+    cancel::unregister_process_group(judge_pgid);
+    cancel::unregister_process_group(solution_pgid);
+    for relay in relays {
+        let _ = relay.await;
+    }
+
+    let ((solution_status, elapsed, timed_out), judge_status) = waited.context("中断されました")?;
+    let mut solution: ExitStatus = solution_status
+        .context("解答の待機中にエラーが発生しました")?
+        .into();
+    solution.timed_out = timed_out;
+    solution.time = Some(elapsed.as_secs_f64());
+
+    // 待ちきれなかったジャッジは上で止めてあるので、終了を回収する
+    let judge_timed_out = judge_status.is_none();
+    let judge_status = match judge_status {
+        Some(status) => status,
+        None => judge.wait().await,
+    };
+    let mut judge: ExitStatus = judge_status
+        .context("ジャッジの待機中にエラーが発生しました")?
+        .into();
+    judge.timed_out = judge_timed_out;
+
+    Ok(Outcome {
+        solution,
+        judge,
+        elapsed,
+        timed_out,
+    })
+}
+
+fn spawn(
+    commandline: &str,
+    dir: &Path,
+    env: &BTreeMap<String, String>,
+    stderr: &str,
+) -> Result<Child> {
+    let errfile =
+        File::create(dir.join(stderr)).with_context(|| format!("{stderr} が作成できません"))?;
+
+    let mut command = std::process::Command::new("bash");
+    command
+        .current_dir(dir)
+        .envs(env)
+        .arg("-c")
+        .arg(commandline)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::from(errfile));
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+
+    Command::from(command)
+        .kill_on_drop(true)
+        .spawn()
+        .context("プロセスが起動できません")
+}
+
+/// from の出力を to に流す。 from が閉じたら to も閉じる
+async fn relay(
+    mut from: impl AsyncRead + Unpin,
+    mut to: impl AsyncWrite + Unpin,
+    transcript: Option<Arc<Mutex<File>>>,
+    prefix: &str,
+) {
+    let mut buf = vec![0; 1 << 13];
+    // 逆向きの出力と混ざらないように、記録は1行ずつにする
+    let mut line = vec![];
+
+    loop {
+        let n = match from.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let chunk = &buf[..n];
+
+        if let Some(transcript) = &transcript {
+            line.extend_from_slice(chunk);
+            while let Some(i) = line.iter().position(|&b| b == b'\n') {
+                let rest = line.split_off(i + 1);
+                let done = std::mem::replace(&mut line, rest);
+                record(transcript, prefix, &done);
+            }
+        }
+
+        // 相手が終了していたら、それ以上は送らない
+        if to.write_all(chunk).await.is_err() || to.flush().await.is_err() {
+            break;
+        }
+    }
+
+    if let (Some(transcript), false) = (&transcript, line.is_empty()) {
+        line.push(b'\n');
+        record(transcript, prefix, &line);
+    }
+}
+
+fn record(transcript: &Mutex<File>, prefix: &str, line: &[u8]) {
+    let mut transcript = transcript.lock().unwrap();
+    let _ = write!(transcript, "{prefix} ");
+    let _ = transcript.write_all(line);
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// 3 を送って 6 が返ってくれば正常終了するジャッジ
+    const JUDGE: &str = "echo 3; read x; [ \"$x\" = 6 ]";
+
+    async fn play(solution: &str, time_limit_secs: Option<f64>, dir: &Path) -> Outcome {
+        let config = InteractiveConfig {
+            judge: JUDGE.into(),
+            solution: solution.into(),
+            time_limit_secs,
+            transcript: Some("transcript.txt".into()),
+        };
+        run(&config, dir, &BTreeMap::new(), &Cancel::new())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn connects_judge_and_solution() {
+        let dir = tempfile::tempdir().unwrap();
+        let outcome = play("read n; echo $((n * 2)); echo done >&2", None, dir.path()).await;
+        assert!(outcome.solution.success());
+        assert!(outcome.judge.success());
+        assert_eq!(outcome.verdict(), None);
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("transcript.txt")).unwrap(),
+            "> 3\n< 6\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join(SOLUTION_ERR)).unwrap(),
+            "done\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn judge_failure_is_wrong_answer() {
+        let dir = tempfile::tempdir().unwrap();
+        let outcome = play("read n; echo $n", None, dir.path()).await;
+        assert!(outcome.solution.success());
+        assert!(!outcome.judge.success());
+        assert!(outcome.verdict().unwrap().starts_with("[WA]"));
+    }
+
+    #[tokio::test]
+    async fn stops_solution_over_time_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let outcome = play("sleep 10", Some(0.2), dir.path()).await;
+        assert!(outcome.timed_out);
+        assert!(outcome.solution.timed_out);
+        assert!(outcome.elapsed < Duration::from_secs(5));
+        assert!(outcome.verdict().unwrap().starts_with("[TLE]"));
+    }
+}
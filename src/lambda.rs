@@ -1,151 +1,756 @@
 #[allow(unused_imports)]
 use anyhow::{anyhow, bail, ensure, Context, Result};
 use std::{
+    collections::{BTreeMap, HashMap},
     fmt::Write as _,
     fs::{self, File},
     io::{self, Write as _},
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime},
 };
-use tokio::sync::Semaphore;
 
-use aws_sdk_lambda::{primitives::Blob, Client};
+use aws_config::{retry::RetryConfig, timeout::TimeoutConfig, SdkConfig};
+use aws_credential_types::provider::ProvideCredentials as _;
+use aws_sdk_lambda::{config::Region, error::SdkError, primitives::Blob, types::LogType, Client};
+use base64::engine::{general_purpose, Engine as _};
 use chrono::Local;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
 
 use crate::{
-    config::{Config, FileTransferConfig},
+    artifact_store::ArtifactStore,
+    cancel::Cancel,
+    codec::{Codec, CodecChoice, Compression},
+    compare,
+    concurrency::{AdaptiveLimit, Permit},
+    config::{Config, FileTransferConfig, InteractiveConfig, LambdaClientConfig},
     console_styles::ConsoleStyles,
+    exit_status::ExitStatus,
+    function_url::{FunctionUrl, TooManyRequests},
+    scorer,
+    submission::{self, Submission},
     submission_state::SubmissionStateSingle::*,
-    summary::{CaseSummary, FinalSummary},
+    summary::{Billing, CaseSummary, FinalSummary},
+    transfer,
 };
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// Request/Response の形式を変えたら上げる。0 はバージョンを持たない古い形式
+pub const PROTOCOL_VERSION: u32 = 1;
+
+pub const CAP_BLOB_CACHE: &str = "blob-cache";
+pub const CAP_ARTIFACT_STORE: &str = "artifact-store";
+pub const CAP_ARCHIVE: &str = "archive";
+pub const CAP_MODE: &str = "mode";
+pub const CAP_CODEC_NONE: &str = "codec-none";
+pub const CAP_CODEC_ZSTD: &str = "codec-zstd";
+pub const CAP_STREAM: &str = "stream";
+pub const CAP_RESULT_UPLOAD: &str = "result-upload";
+pub const CAP_INTERACTIVE: &str = "interactive";
+pub const CAP_ENV: &str = "env";
+
+/// この bootstrap が対応している機能
+pub const CAPABILITIES: &[&str] = &[
+    CAP_BLOB_CACHE,
+    CAP_ARTIFACT_STORE,
+    CAP_ARCHIVE,
+    CAP_MODE,
+    CAP_CODEC_NONE,
+    CAP_CODEC_ZSTD,
+    CAP_STREAM,
+    CAP_RESULT_UPLOAD,
+    CAP_INTERACTIVE,
+    CAP_ENV,
+];
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct SendItem {
     pub path: PathBuf,
+    /// ハッシュだけを送るときは空
+    #[serde(default)]
     pub data: String,
+    /// 展開後の内容の SHA-256 。指定すると Lambda 側の /tmp にキャッシュされる
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// data が空のとき、 codec で圧縮済みの内容をこの署名付き URL から取得する
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// true なら内容は tar で、 path をディレクトリとして展開する
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub archive: bool,
+    /// 展開後のファイルの権限。 tar では各エントリのヘッダに入っている
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// 展開後のファイルの更新時刻（UNIX 時間の秒）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Codec::is_deflate")]
+    pub codec: Codec,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Request {
+    #[serde(default)]
+    pub protocol: u32,
+    /// このリクエストの処理に必要な機能。1つでも足りなければ何も実行されない
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requires: Vec<String>,
+    /// true なら何も実行せず、 bootstrap のバージョンだけを返す
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub probe: bool,
     pub send: Vec<SendItem>,
     pub collect: Vec<PathBuf>,
+    /// 回収するファイルのアップロード先（署名付き URL）
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub upload: HashMap<PathBuf, String>,
+    /// これより大きい回収ファイルは upload に送る
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inline_limit: Option<u64>,
+    /// 回収するファイルの圧縮の指定。ないものは deflate
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub compression: HashMap<PathBuf, Compression>,
+    /// 非同期実行のとき、 Response を deflate してここに置く（署名付き URL か file:// パス）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result_url: Option<String>,
+    /// 指定されていれば start.sh の代わりに対話型の実行をする
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interactive: Option<InteractiveConfig>,
+    /// start.sh や対話型のコマンドに渡す環境変数
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct CollectedItem {
     pub path: PathBuf,
     pub data: String,
+    /// true なら data は空で、 Request::upload の URL にアップロード済み
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub uploaded: bool,
+    /// true なら内容は tar （ディレクトリや glob パターンを回収したとき）
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub archive: bool,
+    #[serde(default, skip_serializing_if = "Codec::is_deflate")]
+    pub codec: Codec,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Response {
     pub message: String,
     pub collected: Vec<CollectedItem>,
+    /// キャッシュになかったハッシュ。空でなければ何も実行されていない
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<String>,
+    #[serde(default)]
+    pub protocol: u32,
+    /// bootstrap のバージョン
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Request::requires のうち対応していないもの。空でなければ何も実行されていない
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unsupported: Vec<String>,
+    /// bootstrap で実行が中断された理由。 message と collected にはそこまでの分が入る
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// start.sh の終了の仕方。状態への反映は judge 側の設定で行う
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_status: Option<ExitStatus>,
+}
+
+/// 関数 URL から応答をストリームで返すときの1行（NDJSON）。
+/// 最後に Done が届かなければ、実行が途中で終わったということ
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum StreamEvent {
+    Log {
+        line: String,
+    },
+    Collected {
+        item: CollectedItem,
+    },
+    /// message と collected は送信済みなので空
+    Done {
+        response: Response,
+    },
+}
+
+impl Request {
+    /// 送信内容から、 bootstrap に必要な機能を求める
+    pub fn requirements(&self) -> Vec<String> {
+        let mut requires = vec![];
+        let mut require = |cap: &str, needed: bool| {
+            if needed && !requires.iter().any(|x| x == cap) {
+                requires.push(cap.to_string());
+            }
+        };
+
+        for item in &self.send {
+            require(CAP_BLOB_CACHE, item.hash.is_some());
+            require(CAP_ARTIFACT_STORE, item.url.is_some());
+            require(CAP_ARCHIVE, item.archive);
+            require(CAP_MODE, item.mode.is_some() || item.mtime.is_some());
+            require(CAP_CODEC_NONE, item.codec == Codec::None);
+            require(CAP_CODEC_ZSTD, item.codec == Codec::Zstd);
+        }
+        require(CAP_ARTIFACT_STORE, !self.upload.is_empty());
+        require(CAP_RESULT_UPLOAD, self.result_url.is_some());
+        require(CAP_INTERACTIVE, self.interactive.is_some());
+        require(CAP_ENV, !self.env.is_empty());
+        for compression in self.compression.values() {
+            let codec = compression.codec;
+            require(
+                CAP_CODEC_NONE,
+                matches!(codec, CodecChoice::None | CodecChoice::Auto),
+            );
+            require(
+                CAP_CODEC_ZSTD,
+                matches!(codec, CodecChoice::Zstd | CodecChoice::Auto),
+            );
+        }
+
+        requires
+    }
+}
+
+impl Response {
+    /// この bootstrap のバージョンを入れた応答
+    pub fn new(message: String, collected: Vec<CollectedItem>) -> Self {
+        Response {
+            message,
+            collected,
+            missing: vec![],
+            protocol: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").into(),
+            capabilities: CAPABILITIES.iter().map(|x| x.to_string()).collect(),
+            unsupported: vec![],
+            error: None,
+            exit_status: None,
+        }
+    }
+
+    /// 実行が中断されたときの応答
+    pub fn failed(message: String, collected: Vec<CollectedItem>, error: String) -> Self {
+        Response {
+            error: Some(error),
+            ..Response::new(message, collected)
+        }
+    }
+
+    /// requires のうち、この bootstrap が対応していないもの
+    pub fn unsupported(requires: &[String]) -> Vec<String> {
+        requires
+            .iter()
+            .filter(|cap| !CAPABILITIES.contains(&cap.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    /// probe の応答から、 requires のうち対応していないもの
+    pub fn missing(&self, requires: &[String]) -> Vec<String> {
+        requires
+            .iter()
+            .filter(|cap| !self.capabilities.contains(cap))
+            .cloned()
+            .collect()
+    }
+
+    pub fn remote_version(&self) -> String {
+        if self.protocol == 0 {
+            "不明（プロトコル 0）".into()
+        } else {
+            format!("{}（プロトコル {}）", self.version, self.protocol)
+        }
+    }
+
+    /// request を処理できない bootstrap からの応答なら、その理由
+    pub fn incompatibility(&self, request: &Request) -> Option<String> {
+        let unsupported = if self.protocol == 0 {
+            // 古い bootstrap は知らないフィールドを無視して実行してしまう
+            request.requires.clone()
+        } else {
+            self.unsupported.clone()
+        };
+
+        (!unsupported.is_empty()).then(|| {
+            format!(
+                "bootstrap {} が {} に対応していません（judge {}、プロトコル {}）。 bootstrap のイメージを更新するか、設定で使う機能を減らしてください",
+                self.remote_version(),
+                unsupported.join(", "),
+                env!("CARGO_PKG_VERSION"),
+                PROTOCOL_VERSION,
+            )
+        })
+    }
+}
+
+/// テストケース以外の送信ファイルは、1回の実行につき1度だけ圧縮する
+#[derive(Debug, Clone)]
+pub(crate) struct StaticBlob {
+    hash: String,
+    /// オブジェクトストレージに置いたときは空
+    data: String,
+    stored: bool,
+    archive: bool,
+    mode: Option<u32>,
+    mtime: Option<i64>,
+    codec: Codec,
+}
+
+/// 送信するファイルの内容と属性
+struct SendData {
+    bytes: Vec<u8>,
+    archive: bool,
+    mode: Option<u32>,
+    mtime: Option<i64>,
 }
 
 struct ParallelArg {
     subm_dir: PathBuf,
     config: Config,
+    label: Option<String>,
     cs: ConsoleStyles,
     summary: Mutex<FinalSummary>,
-    semaphore: Semaphore,
+    limit: Arc<AdaptiveLimit>,
+    /// 比較と採点は Lambda の枠を返してから、 local.parallel の枠で行う
+    scoring: Arc<Semaphore>,
+    cancel: Cancel,
+    blobs: HashMap<PathBuf, StaticBlob>,
+    store: Option<ArtifactStore>,
+    client: Client,
+    function_url: Option<FunctionUrl>,
+    /// 関数 URL では LogResult が返らないので、関数の設定から読んでおく
+    memory_mb: Option<u32>,
 }
 
-pub async fn run_all(
-    casefiles: &[PathBuf],
-    subm_dir: &Path,
-    config: &Config,
-    cs: &ConsoleStyles,
-) -> FinalSummary {
-    if let Some(commandline) = &config.lambda.pre {
-        println!("{}", cs.cyan.apply_to("=> pre コマンドの実行"));
-        if let Err(e) = crate::run_command(commandline) {
-            println!("pre の実行に失敗しました: {e:?}");
+/// pre から post までの1回の実行。複数の提出を同じケースで実行するときは、ケースごとに交互に並べて、
+/// 同時実行数の調整も共有する。送信ファイル以外の Lambda の設定は最初の提出のものを使う。
+/// ケースを何回かに分けて実行しても、クライアントや送信ファイル、同時実行数の調整は最初に用意したものを使い続ける
+pub struct Session {
+    args: Vec<Arc<ParallelArg>>,
+    limit: Arc<AdaptiveLimit>,
+    post: Option<String>,
+    cs: ConsoleStyles,
+    cancel: Cancel,
+}
+
+impl Session {
+    /// pre を実行して、クライアントと送信ファイルを用意する。
+    /// 認証情報がないときや、 casefiles の最初のケースに必要な機能に bootstrap が対応していないときはエラー
+    pub async fn start(
+        casefiles: &[PathBuf],
+        submissions: &[Submission],
+        cs: &ConsoleStyles,
+        cancel: &Cancel,
+    ) -> Result<Self> {
+        let config = &submissions[0].config;
+
+        // pre で時間のかかるビルドをする前に、認証情報を確かめる
+        let sdk_config = load_sdk_config(&config.lambda.client)
+            .await
+            .context("AWS のクライアントが作成できません")?;
+        let client = Client::new(&sdk_config);
+
+        if let Some(commandline) = &config.lambda.pre {
+            println!("{}", cs.cyan.apply_to("=> pre コマンドの実行"));
+            if let Err(e) = crate::run_command(commandline) {
+                println!("pre の実行に失敗しました: {e:?}");
+            }
+        };
+        for submission in submissions {
+            submission.save_provenance("lambda", &submission.config.lambda.send, casefiles);
         }
-    };
 
-    let arg = Arc::new(ParallelArg {
-        subm_dir: subm_dir.to_path_buf(),
-        config: config.clone(),
-        cs: cs.clone(),
-        summary: Mutex::new(FinalSummary::zero(config.subm_id)),
-        semaphore: Semaphore::new(config.lambda.parallel),
-    });
+        let store = match &config.lambda.artifact_store {
+            Some(store_config) => match ArtifactStore::new(store_config, &sdk_config).await {
+                Ok(store) => Some(store),
+                Err(e) => {
+                    println!("オブジェクトストレージを使わずに続行します: {e:?}");
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let function_url = match &config.lambda.function_url {
+            Some(url) => match FunctionUrl::new(url, &sdk_config).await {
+                Ok(function_url) => Some(function_url),
+                Err(e) => {
+                    println!("関数 URL を使わずに続行します: {e:#}");
+                    None
+                }
+            },
+            None => None,
+        };
 
-    let parallel: Vec<_> = casefiles
-        .iter()
-        .map(|casefile| create_parallel(casefile.clone(), arg.clone()))
-        .collect();
+        // 確認できなかったときは、ケースごとの応答で対応していない機能を確かめる
+        let remote = match probe(&client, config).await {
+            Ok(remote) => {
+                println!(
+                    "{}",
+                    cs.dim
+                        .apply_to(format!("bootstrap {}", remote.remote_version()))
+                );
+                Some(remote)
+            }
+            Err(e) => {
+                println!("bootstrap のバージョンが確認できません: {e:#}");
+                None
+            }
+        };
+
+        let memory_mb = match &function_url {
+            Some(_) => match memory_size(&client, config).await {
+                Ok(memory_mb) => Some(memory_mb),
+                Err(e) => {
+                    println!("メモリサイズが取得できないため、関数 URL での課金時間は記録しません: {e:#}");
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let limit = AdaptiveLimit::new(
+            config.lambda.parallel,
+            config.lambda.max_parallel,
+            config.lambda.latency_factor,
+        );
+        let scoring = Arc::new(Semaphore::new(config.local.parallel));
+        let mut args = vec![];
+        for submission in submissions {
+            let blobs = prepare_blobs(&submission.config, store.as_ref(), false, cs).await;
+            args.push(Arc::new(ParallelArg {
+                subm_dir: submission.subm_dir.clone(),
+                config: submission.config.clone(),
+                label: submission.label.clone(),
+                cs: cs.clone(),
+                summary: Mutex::new(FinalSummary::zero(submission.config.subm_id)),
+                limit: limit.clone(),
+                scoring: scoring.clone(),
+                cancel: cancel.clone(),
+                blobs,
+                store: store.clone(),
+                client: client.clone(),
+                function_url: function_url.clone(),
+                memory_mb,
+            }));
+        }
+
+        if let (Some(remote), Some(casefile)) = (&remote, casefiles.first()) {
+            check_capabilities(remote, &args, casefile).await?;
+        }
+
+        Ok(Session {
+            args,
+            limit,
+            post: config.lambda.post.clone(),
+            cs: cs.clone(),
+            cancel: cancel.clone(),
+        })
+    }
+
+    /// targets （start に渡した提出の位置）の提出で casefiles を実行し、それまでの要約に加える
+    pub async fn run(&self, casefiles: &[PathBuf], targets: &[usize]) {
+        let parallel: Vec<_> = casefiles
+            .iter()
+            .flat_map(|casefile| {
+                targets
+                    .iter()
+                    .map(|&i| create_parallel(casefile.clone(), self.args[i].clone()))
+            })
+            .collect();
+
+        for p in parallel {
+            p.await.unwrap();
+        }
+    }
 
-    for p in parallel {
-        p.await.unwrap();
+    /// ここまでの要約。中断されていれば incomplete にする
+    pub fn summaries(&self) -> Vec<FinalSummary> {
+        self.args
+            .iter()
+            .map(|arg| {
+                let mut summary = arg.summary.lock().unwrap().to_owned();
+                summary.concurrency = Some(self.limit.stats());
+                summary.incomplete = self.cancel.is_cancelled();
+                summary
+            })
+            .collect()
     }
 
-    if let Some(commandline) = &config.lambda.post {
-        println!("{}", cs.cyan.apply_to("=> post コマンドの実行"));
-        if let Err(e) = crate::run_command(commandline) {
-            println!("post の実行に失敗しました: {e:?}");
+    /// 中断されていなければ post を実行する
+    pub fn finish(self) -> Vec<FinalSummary> {
+        let summaries = self.summaries();
+        if self.cancel.is_cancelled() {
+            return summaries;
+        }
+
+        if let Some(commandline) = &self.post {
+            println!("{}", self.cs.cyan.apply_to("=> post コマンドの実行"));
+            if let Err(e) = crate::run_command(commandline) {
+                println!("post の実行に失敗しました: {e:?}");
+            }
+        };
+
+        summaries
+    }
+}
+
+/// 最初のケースのリクエストに必要な機能が bootstrap にそろっているか、ケースを始める前に確かめる
+async fn check_capabilities(
+    remote: &Response,
+    args: &[Arc<ParallelArg>],
+    casefile: &Path,
+) -> Result<()> {
+    let casename = submission::case_name(casefile);
+    for arg in args {
+        let mut msg = String::new();
+        let Ok(mut request) = build_request(
+            &arg.config,
+            &arg.blobs,
+            arg.store.as_ref(),
+            &casename,
+            casefile,
+            false,
+            &mut msg,
+        )
+        .await
+        else {
+            // 作れないケースはそのケースだけ IE になる
+            continue;
+        };
+        if arg.function_url.is_some() {
+            request.requires.push(CAP_STREAM.into());
         }
-    };
 
-    arg.clone().summary.lock().unwrap().to_owned()
+        let missing = remote.missing(&request.requires);
+        ensure!(
+            missing.is_empty(),
+            "bootstrap {} が {} に対応していないため、実行しません（judge {}、プロトコル {}）。 bootstrap のイメージを更新するか、設定で使う機能を減らしてください",
+            remote.remote_version(),
+            missing.join(", "),
+            env!("CARGO_PKG_VERSION"),
+            PROTOCOL_VERSION,
+        );
+    }
+    Ok(())
 }
 
 fn create_parallel(casefile: PathBuf, arg: Arc<ParallelArg>) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
-        let _permit = arg.semaphore.acquire().await.unwrap();
-        let casename = casefile.file_stem().unwrap().to_str().unwrap();
-        let casefile = casefile.to_path_buf();
-        let casedir = PathBuf::from(arg.subm_dir.join(format!("c_{casename}")));
+        let mut permit = Some(arg.limit.acquire().await);
+        if arg.cancel.is_cancelled() {
+            return;
+        }
+        let casename = &submission::case_name(&casefile);
+        let casedir = submission::case_dir(&arg.subm_dir, casename);
         fs::create_dir_all(&casedir).unwrap();
 
-        let case_summary = run_each(casename, &casefile, &casedir, &arg.config).await;
+        let Some(case_summary) = run_each(casename, &casefile, &casedir, &arg, &mut permit).await
+        else {
+            return;
+        };
+
+        if let Err(e) = case_summary.save_result(&casedir) {
+            println!("ケースの結果が保存できません: {e:?}");
+        }
 
+        let line = case_summary.labeled(arg.label.as_deref());
         if case_summary.state == AC as u32 {
-            println!("{}", case_summary);
+            println!("{line}");
         } else {
-            println!("{}", arg.cs.red.apply_to(&case_summary));
+            println!("{}", arg.cs.red.apply_to(line));
         }
 
         let mut summary = arg.summary.lock().unwrap();
-        *summary = summary.next_case(&case_summary);
+        summary.next_case(&case_summary);
     })
 }
 
-async fn run_each(casename: &str, casefile: &Path, resdir: &Path, config: &Config) -> CaseSummary {
+async fn run_each(
+    casename: &str,
+    casefile: &Path,
+    resdir: &Path,
+    arg: &ParallelArg,
+    permit: &mut Option<Permit>,
+) -> Option<CaseSummary> {
+    let (config, cancel) = (&arg.config, &arg.cancel);
     let mut msg = String::new();
     writeln!(msg, "[CLI] 提出ID: {}, ケース: {casename}", config.subm_id).unwrap();
     writeln!(msg, "[CLI] 開始時刻: {}", Local::now()).unwrap();
 
-    let mut summary = match lambda_request(config, casefile, &resdir, &mut msg).await {
-        Ok(()) => CaseSummary::zero(casename, AC as u32),
-        Err(()) => CaseSummary::zero(casename, IE as u32),
+    let mut billing = Billing::default();
+    let result = tokio::select! {
+        result = lambda_request(arg, permit, casename, casefile, resdir, &mut msg, &mut billing) => Some(result),
+        _ = cancel.cancelled() => None,
     };
 
+    if result.is_none() {
+        writeln!(
+            msg,
+            "[CLI] 中断されました（AWS Lambda の応答は破棄されます）"
+        )
+        .unwrap();
+        // 結果は残らないが、終わった呼び出しの分は課金されている
+        if billing.invocations > 0 {
+            arg.summary.lock().unwrap().add_billing(&billing);
+        }
+    }
+
     let mut msgfile = File::create(resdir.join("message.txt")).unwrap();
     msgfile.write_all(msg.as_bytes()).unwrap();
     drop(msgfile);
 
-    for file in &config.parse_result.files {
-        if let Ok(s2) = &CaseSummary::parse_file(casename, &resdir.join(file), config) {
-            summary = summary.merge(s2);
-        }
+    let result = result?;
+    // 比較と採点の間は、 Lambda の枠をほかのケースに回す
+    *permit = None;
+    let _scoring = arg.scoring.acquire().await.unwrap();
+    let mut summary = summarize(casename, casefile, resdir, config, result).await;
+    if billing.invocations > 0 {
+        summary.billing = Some(billing);
     }
+    Some(summary)
+}
 
-    summary
+/// 回収したファイルからケースの結果を読む
+pub(crate) async fn summarize(
+    casename: &str,
+    casefile: &Path,
+    resdir: &Path,
+    config: &Config,
+    result: Result<Option<ExitStatus>, ()>,
+) -> CaseSummary {
+    let summary = CaseSummary::parse_case(casename, resdir, config, result);
+    let summary = compare::apply(summary, casefile, resdir, config.compare.as_ref()).await;
+    scorer::apply(summary, casefile, resdir, config.scorer.as_ref()).await
 }
 
 async fn lambda_request(
-    config: &Config,
+    arg: &ParallelArg,
+    permit: &mut Option<Permit>,
+    casename: &str,
     casefile: &Path,
     resdir: &Path,
     msg: &mut String,
-) -> Result<(), ()> {
+    billing: &mut Billing,
+) -> Result<Option<ExitStatus>, ()> {
+    let (config, blobs, store) = (&arg.config, &arg.blobs, arg.store.as_ref());
+    let mut request = build_request(config, blobs, store, casename, casefile, false, msg).await?;
+
+    let mut response = call(arg, permit, &request, casename, resdir, msg, billing).await?;
+    if let Some(reason) = response.incompatibility(&request) {
+        msg.push_str(&response.message);
+        writeln!(msg, "[CLI] [IE] {reason}").unwrap();
+        return Err(());
+    }
+
+    if !response.missing.is_empty() {
+        writeln!(
+            msg,
+            "[CLI] キャッシュにないファイルを {} 件再送信",
+            response.missing.len()
+        )
+        .unwrap();
+
+        for item in &mut request.send {
+            let Some(hash) = &item.hash else { continue };
+            if !response.missing.contains(hash) {
+                continue;
+            }
+            if let Some(blob) = blobs.values().find(|blob| &blob.hash == hash) {
+                item.data = blob.data.clone();
+            }
+        }
+
+        response = call(arg, permit, &request, casename, resdir, msg, billing).await?;
+        if !response.missing.is_empty() {
+            writeln!(
+                msg,
+                "[CLI] [IE] 再送信後もファイルが揃いません: {:?}",
+                response.missing
+            )
+            .unwrap();
+            return Err(());
+        }
+    }
+
+    receive_response(&response, config, store, casename, resdir, msg).await
+}
+
+/// ログと回収ファイルを保存する。 bootstrap で実行が中断されていれば、その理由も残して IE にする
+pub(crate) async fn receive_response(
+    response: &Response,
+    config: &Config,
+    store: Option<&ArtifactStore>,
+    casename: &str,
+    resdir: &Path,
+    msg: &mut String,
+) -> Result<Option<ExitStatus>, ()> {
+    msg.push_str(&response.message);
+
+    for item in &response.collected {
+        save_collected_or_log(item, config, store, casename, resdir, msg).await;
+    }
+
+    match &response.error {
+        Some(error) => {
+            writeln!(msg, "[CLI] [IE] bootstrap で実行が中断されました: {error}").unwrap();
+            Err(())
+        }
+        None => Ok(response.exit_status.clone()),
+    }
+}
+
+/// 1ケース分のリクエストを作る。
+/// inline なら、 Lambda 側にキャッシュがあるかどうかに関わらず静的ファイルの中身も入れる
+pub(crate) async fn build_request(
+    config: &Config,
+    blobs: &HashMap<PathBuf, StaticBlob>,
+    store: Option<&ArtifactStore>,
+    casename: &str,
+    casefile: &Path,
+    inline: bool,
+    msg: &mut String,
+) -> Result<Request, ()> {
     let mut send = vec![];
 
     for t in &config.lambda.send {
-        match prepare_send(t, casefile) {
+        if let Some(blob) = blobs.get(&t.from) {
+            let url = match (blob.stored, store) {
+                (true, Some(store)) => {
+                    match store
+                        .presign("GET", &store.blob_key(&blob.hash, blob.codec))
+                        .await
+                    {
+                        Ok(url) => Some(url),
+                        Err(e) => {
+                            writeln!(msg, "[CLI] [IE] 署名付き URL が作れません: {e:#?}").unwrap();
+                            return Err(());
+                        }
+                    }
+                }
+                _ => None,
+            };
+            send.push(SendItem {
+                path: t.to.clone(),
+                data: if inline {
+                    blob.data.clone()
+                } else {
+                    String::new()
+                },
+                hash: Some(blob.hash.clone()),
+                url,
+                archive: blob.archive,
+                mode: blob.mode,
+                mtime: blob.mtime,
+                codec: blob.codec,
+            });
+            continue;
+        }
+
+        match prepare_send(t, casefile, config.lambda.compression(t), store).await {
             Ok(senditem) => send.push(senditem),
             Err(e) => {
                 writeln!(
@@ -165,78 +770,595 @@ async fn lambda_request(
         .map(|t| t.from.clone())
         .collect();
 
-    let request = Request { send, collect };
+    let mut upload = HashMap::new();
+    if let Some(store) = store {
+        for t in &config.lambda.collect {
+            let key = store.collect_key(config.subm_id, casename, &t.from.to_string_lossy());
+            match store.presign("PUT", &key).await {
+                Ok(url) => {
+                    upload.insert(t.from.clone(), url);
+                }
+                Err(e) => {
+                    writeln!(msg, "[CLI] [IE] 署名付き URL が作れません: {e:#?}").unwrap();
+                    return Err(());
+                }
+            }
+        }
+    }
+
+    let compression = config
+        .lambda
+        .collect
+        .iter()
+        .map(|t| (t.from.clone(), config.lambda.compression(t)))
+        .filter(|(_, compression)| !compression.is_default())
+        .collect();
+
+    let mut request = Request {
+        protocol: PROTOCOL_VERSION,
+        send,
+        collect,
+        upload,
+        inline_limit: store.map(|store| store.inline_limit()),
+        compression,
+        interactive: config.interactive.clone(),
+        env: config.env.clone(),
+        ..Default::default()
+    };
+    request.requires = request.requirements();
+
+    Ok(request)
+}
+
+/// 呼び出しの失敗。 Throttled のときは msg に書かない
+enum CallError {
+    Throttled,
+    /// 接続できなかった。リクエストは届いていないので、やり直しても2回実行されることはない
+    Connection,
+    Failed,
+}
+
+/// スロットリングされたら同時実行数を下げ、間隔を空けて再試行する。待っている間は permit を返しておく。
+/// 接続できなかったときは lambda.client.max_attempts 回まで試す。
+/// それ以外（タイムアウトなど）は Lambda 側で実行が始まっているかもしれないので再試行しない
+async fn call(
+    arg: &ParallelArg,
+    permit: &mut Option<Permit>,
+    request: &Request,
+    casename: &str,
+    resdir: &Path,
+    msg: &mut String,
+    billing: &mut Billing,
+) -> Result<Response, ()> {
+    let max_attempts = arg
+        .config
+        .lambda
+        .client
+        .max_attempts
+        .unwrap_or(DEFAULT_MAX_ATTEMPTS);
+    let (mut throttled, mut disconnected) = (0, 0);
+
+    loop {
+        let (invocations, billed_ms) = (billing.invocations, billing.billed_ms);
+        let start = Instant::now();
+        let result = call_once(arg, request, casename, resdir, msg, billing).await;
+        let elapsed = start.elapsed();
+        // LogResult から課金時間が読めたときだけ、解答の実行時間を除いた待ち時間が分かる
+        let overhead = (billing.invocations > invocations).then(|| {
+            elapsed.saturating_sub(Duration::from_secs_f64(
+                (billing.billed_ms - billed_ms) / 1000.,
+            ))
+        });
+        if let (Some(_), Some(memory_mb), false) = (
+            &arg.function_url,
+            arg.memory_mb,
+            matches!(result, Err(CallError::Throttled | CallError::Connection)),
+        ) {
+            let billed_ms = elapsed.as_secs_f64() * 1000.;
+            billing.add_invocation(billed_ms.ceil(), memory_mb, true);
+        }
+
+        match result {
+            Ok(response) => {
+                arg.limit.on_success(overhead);
+                return Ok(response);
+            }
+            Err(CallError::Failed) => return Err(()),
+            Err(CallError::Throttled) => {
+                arg.limit.on_throttle();
+                throttled += 1;
+                if throttled >= THROTTLE_RETRIES {
+                    writeln!(
+                        msg,
+                        "[CLI] [IE] スロットリングが {THROTTLE_RETRIES} 回続いたため諦めました"
+                    )
+                    .unwrap();
+                    return Err(());
+                }
+                let wait = backoff(throttled - 1);
+                writeln!(
+                    msg,
+                    "[CLI] スロットリングされたため {} ms 後に再試行",
+                    wait.as_millis()
+                )
+                .unwrap();
+                wait_released(arg, permit, wait).await;
+            }
+            Err(CallError::Connection) => {
+                disconnected += 1;
+                if disconnected >= max_attempts {
+                    writeln!(
+                        msg,
+                        "[CLI] [IE] {max_attempts} 回続けて接続できなかったため諦めました"
+                    )
+                    .unwrap();
+                    return Err(());
+                }
+                let wait = backoff(disconnected - 1);
+                writeln!(msg, "[CLI] {} ms 後に再試行", wait.as_millis()).unwrap();
+                wait_released(arg, permit, wait).await;
+            }
+        }
+    }
+}
+
+/// 下げた同時実行数がすぐに効くように、待つ間は枠を空けて、ほかのケースと同じく取り直す
+async fn wait_released(arg: &ParallelArg, permit: &mut Option<Permit>, wait: Duration) {
+    *permit = None;
+    tokio::time::sleep(wait).await;
+    *permit = Some(arg.limit.acquire().await);
+}
+
+/// スロットリングで再試行する回数（最初の1回を含む）
+const THROTTLE_RETRIES: u32 = 8;
+/// lambda.client.max_attempts がないときの、接続の試行回数（SDK の既定と同じ）
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+/// リクエストを送る前の接続で失敗したか
+fn is_connect_error(e: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if e.downcast_ref::<hyper::Error>()
+            .is_some_and(|e| e.is_connect())
+        {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// 指数的に伸ばし、ケース同士がそろわないように揺らぎを加える
+fn backoff(attempt: u32) -> Duration {
+    let base = Duration::from_millis(500 * (1 << attempt.min(5)));
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    base + base.mul_f64(nanos as f64 / 1e9)
+}
+
+/// 関数 URL があれば応答をストリームで受け取る。回収したファイルは届いた時点で保存する
+async fn call_once(
+    arg: &ParallelArg,
+    request: &Request,
+    casename: &str,
+    resdir: &Path,
+    msg: &mut String,
+    billing: &mut Billing,
+) -> Result<Response, CallError> {
+    let Some(function_url) = &arg.function_url else {
+        return invoke(&arg.client, &arg.config, request, msg, billing).await;
+    };
 
-    let request_json = match serde_json::to_vec(&request) {
+    let request_json = match serde_json::to_vec(request) {
         Ok(v) => v,
         Err(e) => {
             writeln!(msg, "[CLI] [IE] JSON化できません: {request:?}\n{e:#?}").unwrap();
-            return Err(());
+            return Err(CallError::Failed);
+        }
+    };
+
+    let mut lines = match function_url.post(request_json).await {
+        Ok(lines) => lines,
+        Err(e) if e.is::<TooManyRequests>() => return Err(CallError::Throttled),
+        Err(e) if is_connect_error(e.as_ref()) => {
+            writeln!(msg, "[CLI] 接続できません: {e:#}").unwrap();
+            return Err(CallError::Connection);
+        }
+        Err(e) => {
+            writeln!(msg, "[CLI] [IE] 通信エラー: {e:#}").unwrap();
+            return Err(CallError::Failed);
+        }
+    };
+
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) if line.is_empty() => continue,
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                writeln!(
+                    msg,
+                    "[CLI] [IE] 実行の完了前に応答が途切れました（受信済みのログと回収ファイルは保存しています）"
+                )
+                .unwrap();
+                return Err(CallError::Failed);
+            }
+            Err(e) => {
+                writeln!(
+                    msg,
+                    "[CLI] [IE] {e:#}（受信済みのログと回収ファイルは保存しています）"
+                )
+                .unwrap();
+                return Err(CallError::Failed);
+            }
+        };
+
+        match serde_json::from_slice(&line) {
+            Ok(StreamEvent::Log { line }) => msg.push_str(&line),
+            Ok(StreamEvent::Collected { item }) => {
+                let store = arg.store.as_ref();
+                save_collected_or_log(&item, &arg.config, store, casename, resdir, msg).await
+            }
+            Ok(StreamEvent::Done { response }) => return Ok(response),
+            Err(e) => {
+                writeln!(
+                    msg,
+                    "[CLI] [IE] AWS Lambda からの応答が解釈できません: {e}\n{}",
+                    String::from_utf8_lossy(&line)
+                )
+                .unwrap();
+                return Err(CallError::Failed);
+            }
         }
+    }
+}
+
+async fn save_collected_or_log(
+    item: &CollectedItem,
+    config: &Config,
+    store: Option<&ArtifactStore>,
+    casename: &str,
+    resdir: &Path,
+    msg: &mut String,
+) {
+    if let Err(e) = save_collected(item, casename, resdir, config, store).await {
+        writeln!(
+            msg,
+            "[CLI] 回収したファイル {} が保存できませんが続行します: {e:#?}",
+            item.path.display()
+        )
+        .unwrap();
+    }
+}
+
+/// 何も実行せずに、 bootstrap のバージョンと対応している機能を問い合わせる
+pub async fn probe(client: &Client, config: &Config) -> Result<Response> {
+    let request = Request {
+        protocol: PROTOCOL_VERSION,
+        probe: true,
+        ..Default::default()
     };
+    let mut msg = String::new();
+    invoke(client, config, &request, &mut msg, &mut Billing::default())
+        .await
+        .map_err(|e| match e {
+            CallError::Throttled => anyhow!("スロットリングされました"),
+            CallError::Connection | CallError::Failed => anyhow!("{}", msg.trim()),
+        })
+}
+
+/// 1度だけ作り、すべての呼び出しで共有する。認証情報もここで確認する
+pub async fn load_sdk_config(client_config: &LambdaClientConfig) -> Result<SdkConfig> {
+    let mut loader = aws_config::from_env();
+    if let Some(region) = &client_config.region {
+        loader = loader.region(Region::new(region.clone()));
+    }
+    if let Some(profile) = &client_config.profile {
+        loader = loader.profile_name(profile);
+    }
+
+    let mut timeout = TimeoutConfig::builder();
+    if let Some(secs) = client_config.connect_timeout_secs {
+        timeout = timeout.connect_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = client_config.read_timeout_secs {
+        timeout = timeout.read_timeout(Duration::from_secs(secs));
+    }
+    loader = loader.timeout_config(timeout.build());
+
+    if let Some(max_attempts) = client_config.max_attempts {
+        loader = loader.retry_config(RetryConfig::standard().with_max_attempts(max_attempts));
+    }
+
+    let sdk_config = loader.load().await;
+    sdk_config
+        .credentials_provider()
+        .context("AWS の認証情報が設定されていません")?
+        .provide_credentials()
+        .await
+        .context("AWS の認証情報が取得できません")?;
+    ensure!(
+        sdk_config.region().is_some(),
+        "AWS のリージョンが設定されていません"
+    );
+
+    Ok(sdk_config)
+}
+
+/// 関数のメモリサイズ (MB)
+async fn memory_size(client: &Client, config: &Config) -> Result<u32> {
+    let output = client
+        .get_function_configuration()
+        .function_name(&config.lambda.function_name)
+        .send()
+        .await
+        .context("関数の設定が取得できません")?;
+    let memory_mb = output.memory_size().context("メモリサイズがありません")?;
+    Ok(memory_mb as u32)
+}
 
-    let sdk_config = aws_config::from_env().load().await;
+/// LogResult の末尾にある REPORT 行から、課金時間 (ms) とメモリサイズ (MB) を読む
+fn parse_report(log_result: &str) -> Option<(f64, u32)> {
+    let log = general_purpose::STANDARD.decode(log_result).ok()?;
+    let log = String::from_utf8_lossy(&log);
+    static BILLED: OnceLock<Regex> = OnceLock::new();
+    static MEMORY: OnceLock<Regex> = OnceLock::new();
+    let billed = BILLED.get_or_init(|| Regex::new(r"Billed Duration: ([0-9.]+) ms").unwrap());
+    let memory = MEMORY.get_or_init(|| Regex::new(r"Memory Size: ([0-9]+) MB").unwrap());
+    Some((
+        billed.captures(&log)?[1].parse().ok()?,
+        memory.captures(&log)?[1].parse().ok()?,
+    ))
+}
+
+async fn invoke(
+    client: &Client,
+    config: &Config,
+    request: &Request,
+    msg: &mut String,
+    billing: &mut Billing,
+) -> Result<Response, CallError> {
+    let request_json = match serde_json::to_vec(request) {
+        Ok(v) => v,
+        Err(e) => {
+            writeln!(msg, "[CLI] [IE] JSON化できません: {request:?}\n{e:#?}").unwrap();
+            return Err(CallError::Failed);
+        }
+    };
 
-    let output = match Client::new(&sdk_config)
+    // タイムアウトで SDK が再試行すると同じケースが2回実行されるので、再試行は call に任せる
+    let output = match client
         .invoke()
         .function_name(&config.lambda.function_name)
-        .payload(Blob::new(request_json.clone()))
+        .log_type(LogType::Tail)
+        .payload(Blob::new(request_json))
+        .customize()
+        .config_override(aws_sdk_lambda::Config::builder().retry_config(RetryConfig::disabled()))
         .send()
         .await
     {
         Ok(x) => x,
+        Err(SdkError::ServiceError(e)) if e.err().is_too_many_requests_exception() => {
+            return Err(CallError::Throttled);
+        }
+        Err(e) if is_connect_error(&e) => {
+            writeln!(msg, "[CLI] 接続できません: {e:#?}").unwrap();
+            return Err(CallError::Connection);
+        }
         Err(e) => {
             writeln!(msg, "[CLI] [IE] 通信エラー: {e:#?}").unwrap();
-            return Err(());
+            return Err(CallError::Failed);
         }
     };
 
+    match output.log_result().and_then(parse_report) {
+        Some((billed_ms, memory_mb)) => billing.add_invocation(billed_ms, memory_mb, false),
+        None => writeln!(msg, "[CLI] 課金時間がログから読み取れません").unwrap(),
+    }
+
     let response_payload = match output.payload() {
         Some(blob) => blob,
         None => {
             writeln!(msg, "[CLI] [IE] AWS Lambda からの応答が空です").unwrap();
-            return Err(());
+            return Err(CallError::Failed);
         }
     }
     .to_owned()
     .into_inner();
+    let response_payload = strip_http_prelude(&response_payload);
 
-    let response: Response = match serde_json::from_slice(&response_payload) {
-        Ok(r) => r,
-        Err(e) => {
-            writeln!(msg, "[CLI] [IE] AWS Lambda からの応答が空です: {e:#?}").unwrap();
-            return Err(());
-        }
-    };
-
-    writeln!(msg, "{}", response.message).unwrap();
+    if let Some(function_error) = output.function_error() {
+        writeln!(
+            msg,
+            "[CLI] [IE] bootstrap でエラーが発生しました（{function_error}）: {}",
+            String::from_utf8_lossy(response_payload)
+        )
+        .unwrap();
+        return Err(CallError::Failed);
+    }
 
-    for item in &response.collected {
-        if let Err(e) = save_collected(item, resdir, config) {
+    match serde_json::from_slice(response_payload) {
+        Ok(r) => Ok(r),
+        Err(e) => {
             writeln!(
                 msg,
-                "[CLI] 回収したファイル {} が保存できませんが続行します: {e:#?}",
-                item.path.display()
+                "[CLI] [IE] AWS Lambda からの応答が解釈できません（bootstrap のバージョンが合っていない可能性があります）: {e}\n{}",
+                String::from_utf8_lossy(response_payload)
             )
             .unwrap();
+            Err(CallError::Failed)
+        }
+    }
+}
+
+/// DUAL_JUDGE_STREAMING の bootstrap は Invoke にも関数 URL 用の形式で返すので、
+/// 先頭の HTTP の情報（JSON と NUL 8バイト）を取り除く。 JSON の本文には NUL が現れないので、なければそのまま
+fn strip_http_prelude(payload: &[u8]) -> &[u8] {
+    const DELIMITER: &[u8] = &[0; 8];
+    payload
+        .windows(DELIMITER.len())
+        .position(|window| window == DELIMITER)
+        .map_or(payload, |i| &payload[i + DELIMITER.len()..])
+}
+
+/// 静的な送信ファイルを事前に圧縮する。 inline_limit を超えるものと、 store_all ならすべてをストレージに置く
+pub(crate) async fn prepare_blobs(
+    config: &Config,
+    store: Option<&ArtifactStore>,
+    store_all: bool,
+    cs: &ConsoleStyles,
+) -> HashMap<PathBuf, StaticBlob> {
+    let mut blobs = HashMap::new();
+
+    for t in &config.lambda.send {
+        if t.from.to_string_lossy().contains("$casefile") || blobs.contains_key(&t.from) {
+            continue;
+        }
+
+        let blob = match read_send(t, &t.from) {
+            Ok(data) => prepare_blob(data, config.lambda.compression(t), store, store_all).await,
+            Err(e) => Err(e),
+        };
+
+        match blob {
+            Ok(blob) => {
+                blobs.insert(t.from.clone(), blob);
+            }
+            Err(e) => println!(
+                "{}",
+                cs.dim
+                    .apply_to(format!("{} は事前に圧縮できません: {e}", t.from.display()))
+            ),
+        }
+    }
+
+    blobs
+}
+
+async fn prepare_blob(
+    send: SendData,
+    compression: Compression,
+    store: Option<&ArtifactStore>,
+    store_all: bool,
+) -> Result<StaticBlob> {
+    let hash = crate::sha256(&send.bytes);
+    let codec = compression.select(send.bytes.len());
+
+    let (data, stored) = match store {
+        Some(store) if store_all || send.bytes.len() as u64 > store.inline_limit() => {
+            upload_blob(store, &hash, codec, compression.level, &send.bytes).await?;
+            (String::new(), true)
         }
+        _ => (codec.encode(&send.bytes, compression.level)?, false),
+    };
+
+    Ok(StaticBlob {
+        hash,
+        data,
+        stored,
+        archive: send.archive,
+        mode: send.mode,
+        mtime: send.mtime,
+        codec,
+    })
+}
+
+/// 送信するファイルを読む。ディレクトリや glob パターンなら tar にまとめる。
+/// 権限は transfer_config.mode があればそれ、なければ元のファイルのもの
+fn read_send(transfer_config: &FileTransferConfig, from: &Path) -> Result<SendData> {
+    let mode = transfer_config.mode()?;
+
+    if transfer::is_single_file(Path::new(""), from) {
+        let bytes =
+            fs::read(from).with_context(|| format!("ファイル {from:?} が読み取れません"))?;
+        let metadata = fs::metadata(from)?;
+        Ok(SendData {
+            bytes,
+            archive: false,
+            mode: mode.or_else(|| transfer::file_mode(&metadata)),
+            mtime: transfer::file_mtime(&metadata),
+        })
+    } else {
+        Ok(SendData {
+            bytes: transfer::pack(Path::new(""), from, mode)?,
+            archive: true,
+            mode: None,
+            mtime: None,
+        })
     }
+}
 
+/// 内容のハッシュをキーにするので、同じ内容のファイルは1度しかアップロードしない
+async fn upload_blob(
+    store: &ArtifactStore,
+    hash: &str,
+    codec: Codec,
+    level: Option<i32>,
+    bytes: &[u8],
+) -> Result<()> {
+    let key = store.blob_key(hash, codec);
+    if !store.exists(&key).await? {
+        store.put(&key, codec.compress(bytes, level)?).await?;
+    }
     Ok(())
 }
 
-fn prepare_send(transfer_config: &FileTransferConfig, casefile: &Path) -> Result<SendItem> {
+async fn prepare_send(
+    transfer_config: &FileTransferConfig,
+    casefile: &Path,
+    compression: Compression,
+    store: Option<&ArtifactStore>,
+) -> Result<SendItem> {
     let from = Path::new(&transfer_config.from)
         .to_string_lossy()
         .replace("$casefile", &casefile.to_string_lossy());
 
+    let SendData {
+        bytes,
+        archive,
+        mode,
+        mtime,
+    } = read_send(transfer_config, Path::new(&from))?;
+    let codec = compression.select(bytes.len());
+
+    if let Some(store) = store {
+        if bytes.len() as u64 > store.inline_limit() {
+            let hash = crate::sha256(&bytes);
+            upload_blob(store, &hash, codec, compression.level, &bytes).await?;
+            return Ok(SendItem {
+                path: transfer_config.to.clone(),
+                data: String::new(),
+                url: Some(store.presign("GET", &store.blob_key(&hash, codec)).await?),
+                hash: Some(hash),
+                archive,
+                mode,
+                mtime,
+                codec,
+            });
+        }
+    }
+
     Ok(SendItem {
         path: transfer_config.to.clone(),
-        data: crate::encode_file(&PathBuf::from(&from))?,
+        data: codec
+            .encode(&bytes, compression.level)
+            .with_context(|| format!("ファイル {from:?} をエンコードできません"))?,
+        hash: None,
+        url: None,
+        archive,
+        mode,
+        mtime,
+        codec,
     })
 }
 
-fn save_collected(collected: &CollectedItem, resdir: &Path, config: &Config) -> Result<PathBuf> {
+async fn save_collected(
+    collected: &CollectedItem,
+    casename: &str,
+    resdir: &Path,
+    config: &Config,
+    store: Option<&ArtifactStore>,
+) -> Result<PathBuf> {
     let to = &config
         .lambda
         .collect
@@ -245,6 +1367,142 @@ fn save_collected(collected: &CollectedItem, resdir: &Path, config: &Config) ->
         .ok_or(io::Error::from(io::ErrorKind::NotFound))?
         .to;
 
-    crate::decode_file(&collected.data, &resdir.join(to))?;
+    let bytes = if collected.uploaded {
+        let store = store.context("オブジェクトストレージが設定されていません")?;
+        let key = store.collect_key(config.subm_id, casename, &collected.path.to_string_lossy());
+        let bytes = collected.codec.decompress(&store.get(&key).await?)?;
+        // 受け取ったものは残しておく必要がない。消せなくても結果には影響しないので無視する
+        let _ = store.delete(&key).await;
+        bytes
+    } else {
+        collected
+            .codec
+            .decode(collected.data.as_bytes())
+            .with_context(|| format!("データのデコードが失敗しました（パス: {to:?}）"))?
+    };
+
+    if collected.archive {
+        transfer::unpack(&bytes, &resdir.join(to))?;
+    } else {
+        fs::write(resdir.join(to), bytes)
+            .with_context(|| format!("ファイル {:?} に書き込めません", resdir.join(to)))?;
+    }
     Ok(to.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_http_prelude_from_streaming_response() {
+        let body = br#"{"message":"ok"}"#;
+        assert_eq!(strip_http_prelude(body), body);
+
+        let mut streamed = br#"{"statusCode":200,"headers":{}}"#.to_vec();
+        streamed.extend([0; 8]);
+        streamed.extend(body);
+        assert_eq!(strip_http_prelude(&streamed), body);
+    }
+
+    fn item(path: &str) -> SendItem {
+        SendItem {
+            path: path.into(),
+            data: String::new(),
+            hash: None,
+            url: None,
+            archive: false,
+            mode: None,
+            mtime: None,
+            codec: Codec::Deflate,
+        }
+    }
+
+    #[test]
+    fn plain_request_needs_nothing() {
+        let request = Request {
+            send: vec![item("start.sh")],
+            collect: vec!["out.txt".into()],
+            ..Default::default()
+        };
+        assert!(request.requirements().is_empty());
+    }
+
+    #[test]
+    fn requirements_follow_request_contents() {
+        let request = Request {
+            send: vec![
+                SendItem {
+                    hash: Some("0".into()),
+                    mode: Some(0o755),
+                    ..item("a")
+                },
+                SendItem {
+                    url: Some("https://example.com/b".into()),
+                    archive: true,
+                    codec: Codec::Zstd,
+                    ..item("b")
+                },
+                SendItem {
+                    hash: Some("1".into()),
+                    ..item("c")
+                },
+            ],
+            compression: HashMap::from([(
+                PathBuf::from("out.txt"),
+                Compression {
+                    codec: CodecChoice::Auto,
+                    level: None,
+                },
+            )]),
+            result_url: Some("file:///tmp/result".into()),
+            env: BTreeMap::from([("SEED".into(), "1".into())]),
+            ..Default::default()
+        };
+        // 重複せず、見つかった順に並ぶ
+        assert_eq!(
+            request.requirements(),
+            [
+                CAP_BLOB_CACHE,
+                CAP_MODE,
+                CAP_ARTIFACT_STORE,
+                CAP_ARCHIVE,
+                CAP_CODEC_ZSTD,
+                CAP_RESULT_UPLOAD,
+                CAP_ENV,
+                CAP_CODEC_NONE,
+            ]
+        );
+        assert!(request
+            .requirements()
+            .iter()
+            .all(|cap| CAPABILITIES.contains(&cap.as_str())));
+    }
+
+    #[test]
+    fn reads_request_without_protocol() {
+        // バージョンを持たない古い形式。 codec がなければ deflate
+        let request: Request = serde_json::from_str(
+            r#"{"send":[{"path":"start.sh","data":""}],"collect":["out.txt"]}"#,
+        )
+        .unwrap();
+        assert_eq!(request.protocol, 0);
+        assert_eq!(request.send[0].codec, Codec::Deflate);
+        assert!(request.requirements().is_empty());
+        let response: Response =
+            serde_json::from_str(r#"{"message":"ok","collected":[]}"#).unwrap();
+        assert_eq!(response.protocol, 0);
+        assert!(response.unsupported.is_empty());
+    }
+
+    #[test]
+    fn reads_billing_from_report_line() {
+        let log = "START RequestId: 1\nEND RequestId: 1\nREPORT RequestId: 1\tDuration: 1234.56 ms\tBilled Duration: 1235 ms\tMemory Size: 2048 MB\tMax Memory Used: 100 MB\n";
+        let encoded = general_purpose::STANDARD.encode(log);
+        assert_eq!(parse_report(&encoded), Some((1235., 2048)));
+        // ログが切り詰められて REPORT 行がないとき
+        let truncated = general_purpose::STANDARD.encode("START RequestId: 1\n");
+        assert_eq!(parse_report(&truncated), None);
+        assert_eq!(parse_report("%%%"), None);
+    }
+}
@@ -1,9 +1,23 @@
+pub mod artifact_store;
+pub mod batch;
+pub mod cancel;
+pub mod codec;
+pub mod compare;
+pub mod concurrency;
 pub mod config;
 pub mod console_styles;
+pub mod exit_status;
+pub mod function_url;
+pub mod interactive;
 pub mod lambda;
 pub mod local;
+pub mod report;
+pub mod scorer;
+pub mod submission;
 pub mod submission_state;
 pub mod summary;
+pub mod sweep;
+pub mod transfer;
 
 #[allow(unused_imports)]
 use anyhow::{anyhow, bail, ensure, Context, Result};
@@ -14,11 +28,9 @@ use std::{
     process::Command,
 };
 
-use base64::engine::{general_purpose, Engine as _};
-use flate2::{
-    write::{DeflateDecoder, DeflateEncoder},
-    Compression,
-};
+use sha2::{Digest, Sha256};
+
+use crate::codec::Codec;
 
 pub fn now() -> String {
     chrono::Utc::now()
@@ -30,11 +42,11 @@ pub fn comma_sep_int(number: i128) -> String {
     let mut s = number.to_string().into_bytes();
     s.reverse();
     let mut res = vec![];
-    for i in 0..s.len() {
+    for (i, &c) in s.iter().enumerate() {
         if i > 0 && i % 3 == 0 {
             res.push(b',');
         }
-        res.push(s[i]);
+        res.push(c);
     }
     res.reverse();
     String::from_utf8_lossy(&res).into_owned()
@@ -42,7 +54,7 @@ pub fn comma_sep_int(number: i128) -> String {
 
 pub fn run_command(commandline: &str) -> Result<()> {
     let mut child = if cfg!(target_os = "windows") {
-        Command::new("cmd").args(&["/C", commandline]).spawn()?
+        Command::new("cmd").args(["/C", commandline]).spawn()?
     } else {
         Command::new("sh").arg("-c").arg(commandline).spawn()?
     };
@@ -53,6 +65,10 @@ pub fn run_command(commandline: &str) -> Result<()> {
     }
 }
 
+pub fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
 pub fn encode_file(path: &Path) -> Result<String> {
     let data = fs::read(path)
         .with_context(|| format!("エンコード対象ファイル {path:?} が読み取れません"))?;
@@ -60,9 +76,11 @@ pub fn encode_file(path: &Path) -> Result<String> {
 }
 
 pub fn encode(data: &[u8]) -> Result<String> {
-    let mut gz = DeflateEncoder::new(vec![], Compression::default());
-    gz.write_all(&data)?;
-    Ok(general_purpose::STANDARD_NO_PAD.encode(gz.finish()?))
+    Codec::Deflate.encode(data, None)
+}
+
+pub fn deflate(data: &[u8]) -> Result<Vec<u8>> {
+    Codec::Deflate.compress(data, None)
 }
 
 pub fn decode_file(data: &str, path: &Path) -> Result<()> {
@@ -77,8 +95,9 @@ pub fn decode_file(data: &str, path: &Path) -> Result<()> {
 }
 
 pub fn decode(data: &[u8]) -> Result<Vec<u8>> {
-    let bytes = general_purpose::STANDARD_NO_PAD.decode(data)?;
-    let mut gz = DeflateDecoder::new(vec![]);
-    gz.write_all(&bytes)?;
-    Ok(gz.finish()?)
+    Codec::Deflate.decode(data)
+}
+
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>> {
+    Codec::Deflate.decompress(data)
 }
@@ -9,88 +9,175 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+#[cfg(unix)]
+use std::os::unix::process::CommandExt as _;
+
 use tempfile::TempDir;
 use tokio::sync::Semaphore;
 
 use chrono::Local;
 
 use crate::{
+    cancel::{self, Cancel},
+    compare,
     config::Config,
     console_styles::ConsoleStyles,
+    exit_status::ExitStatus,
+    interactive, scorer,
+    submission::{self, Submission},
     submission_state::SubmissionStateSingle::*,
     summary::{CaseSummary, FinalSummary},
+    transfer,
 };
 
 struct ParallelArg {
     subm_dir: PathBuf,
     config: Config,
+    label: Option<String>,
     cs: ConsoleStyles,
     summary: Mutex<FinalSummary>,
-    semaphore: Semaphore,
+    /// 実行時間の条件をそろえるため、比較と採点も実行と同じ枠で行う
+    semaphore: Arc<Semaphore>,
+    cancel: Cancel,
 }
 
-pub async fn run_all(
-    casefiles: &[PathBuf],
-    subm_dir: &Path,
-    config: &Config,
-    cs: &ConsoleStyles,
-) -> FinalSummary {
-    if let Some(commandline) = &config.local.pre {
-        println!("{}", cs.cyan.apply_to("=> pre コマンドの実行"));
-        if let Err(e) = crate::run_command(commandline) {
-            println!("pre の実行に失敗しました: {e:?}");
+/// pre から post までの1回の実行。複数の提出を同じケースで実行するときは、
+/// 条件をそろえるため、ケースごとに交互に並べて同じ枠で実行する。 pre / post と並列数は最初の提出の設定を使う。
+/// ケースを何回かに分けて実行しても、並列の枠は共有する
+pub struct Session {
+    args: Vec<Arc<ParallelArg>>,
+    post: Option<String>,
+    cs: ConsoleStyles,
+    cancel: Cancel,
+}
+
+impl Session {
+    /// pre を実行して、並列の枠を用意する
+    pub fn start(
+        casefiles: &[PathBuf],
+        submissions: &[Submission],
+        cs: &ConsoleStyles,
+        cancel: &Cancel,
+    ) -> Self {
+        let config = &submissions[0].config;
+
+        if let Some(commandline) = &config.local.pre {
+            println!("{}", cs.cyan.apply_to("=> pre コマンドの実行"));
+            if let Err(e) = crate::run_command(commandline) {
+                println!("pre の実行に失敗しました: {e:?}");
+            }
+        };
+        for submission in submissions {
+            submission.save_provenance("local", &submission.config.local.send, casefiles);
         }
-    };
 
-    let arg = Arc::new(ParallelArg {
-        subm_dir: subm_dir.to_path_buf(),
-        config: config.clone(),
-        cs: cs.clone(),
-        summary: Mutex::new(FinalSummary::zero(config.subm_id)),
-        semaphore: Semaphore::new(config.local.parallel),
-    });
-
-    let parallel: Vec<_> = casefiles
-        .iter()
-        .map(|casefile| create_parallel(casefile.clone(), arg.clone()))
-        .collect();
-
-    for p in parallel {
-        p.await.unwrap();
+        let semaphore = Arc::new(Semaphore::new(config.local.parallel));
+        let args = submissions
+            .iter()
+            .map(|submission| {
+                Arc::new(ParallelArg {
+                    subm_dir: submission.subm_dir.clone(),
+                    config: submission.config.clone(),
+                    label: submission.label.clone(),
+                    cs: cs.clone(),
+                    summary: Mutex::new(FinalSummary::zero(submission.config.subm_id)),
+                    semaphore: semaphore.clone(),
+                    cancel: cancel.clone(),
+                })
+            })
+            .collect();
+
+        Session {
+            args,
+            post: config.local.post.clone(),
+            cs: cs.clone(),
+            cancel: cancel.clone(),
+        }
+    }
+
+    /// targets （start に渡した提出の位置）の提出で casefiles を実行し、それまでの要約に加える
+    pub async fn run(&self, casefiles: &[PathBuf], targets: &[usize]) {
+        let parallel: Vec<_> = casefiles
+            .iter()
+            .flat_map(|casefile| {
+                targets
+                    .iter()
+                    .map(|&i| create_parallel(casefile.clone(), self.args[i].clone()))
+            })
+            .collect();
+
+        for p in parallel {
+            p.await.unwrap();
+        }
+    }
+
+    /// ここまでの要約。中断されていれば incomplete にする
+    pub fn summaries(&self) -> Vec<FinalSummary> {
+        self.args
+            .iter()
+            .map(|arg| {
+                let mut summary = arg.summary.lock().unwrap().to_owned();
+                summary.incomplete = self.cancel.is_cancelled();
+                summary
+            })
+            .collect()
     }
 
-    if let Some(commandline) = &config.local.post {
-        println!("{}", cs.cyan.apply_to("=> post コマンドの実行"));
-        if let Err(e) = crate::run_command(commandline) {
-            println!("post の実行に失敗しました: {e:?}");
+    /// 中断されていなければ post を実行する
+    pub fn finish(self) -> Vec<FinalSummary> {
+        let summaries = self.summaries();
+        if self.cancel.is_cancelled() {
+            return summaries;
         }
-    };
 
-    arg.clone().summary.lock().unwrap().to_owned()
+        if let Some(commandline) = &self.post {
+            println!("{}", self.cs.cyan.apply_to("=> post コマンドの実行"));
+            if let Err(e) = crate::run_command(commandline) {
+                println!("post の実行に失敗しました: {e:?}");
+            }
+        };
+
+        summaries
+    }
 }
 
 fn create_parallel(casefile: PathBuf, arg: Arc<ParallelArg>) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
         let _permit = arg.semaphore.acquire().await.unwrap();
-        let casename = casefile.file_stem().unwrap().to_str().unwrap();
-        let casefile = casefile.to_path_buf();
-        let casedir = PathBuf::from(arg.subm_dir.join(format!("c_{casename}")));
+        if arg.cancel.is_cancelled() {
+            return;
+        }
+        let casename = &submission::case_name(&casefile);
+        let casedir = submission::case_dir(&arg.subm_dir, casename);
         fs::create_dir_all(&casedir).unwrap();
 
-        let case_summary = run_each(casename, &casefile, &casedir, &arg.config).await;
+        let Some(case_summary) = run_each(casename, &casefile, &casedir, &arg).await else {
+            return;
+        };
 
+        if let Err(e) = case_summary.save_result(&casedir) {
+            println!("ケースの結果が保存できません: {e:?}");
+        }
+
+        let line = case_summary.labeled(arg.label.as_deref());
         if case_summary.state == AC as u32 {
-            println!("{}", case_summary);
+            println!("{line}");
         } else {
-            println!("{}", arg.cs.red.apply_to(&case_summary));
+            println!("{}", arg.cs.red.apply_to(line));
         }
 
         let mut summary = arg.summary.lock().unwrap();
-        *summary = summary.next_case(&case_summary);
+        summary.next_case(&case_summary);
     })
 }
 
-async fn run_each(casename: &str, casefile: &Path, resdir: &Path, config: &Config) -> CaseSummary {
+async fn run_each(
+    casename: &str,
+    casefile: &Path,
+    resdir: &Path,
+    arg: &ParallelArg,
+) -> Option<CaseSummary> {
+    let (config, cancel) = (&arg.config, &arg.cancel);
     let mut msg = String::new();
     writeln!(msg, "[CLI] 提出ID: {}, ケース: {casename}", config.subm_id).unwrap();
     writeln!(msg, "[CLI] 開始時刻: {}", Local::now()).unwrap();
@@ -98,22 +185,23 @@ async fn run_each(casename: &str, casefile: &Path, resdir: &Path, config: &Confi
     let temp_dir = TempDir::new().unwrap();
     writeln!(msg, "[CLI] {} で実行されます", temp_dir.path().display()).unwrap();
 
-    let mut summary = match local_request(&temp_dir, config, casefile, &resdir, &mut msg).await {
-        Ok(()) => CaseSummary::zero(casename, AC as u32),
-        Err(()) => CaseSummary::zero(casename, IE as u32),
-    };
+    let result = local_request(&temp_dir, config, casefile, &resdir, cancel, &mut msg).await;
+
+    if cancel.is_cancelled() {
+        writeln!(msg, "[CLI] 中断されました").unwrap();
+    }
 
     let mut msgfile = File::create(resdir.join("message.txt")).unwrap();
     msgfile.write_all(msg.as_bytes()).unwrap();
     drop(msgfile);
 
-    for file in &config.parse_result.files {
-        if let Ok(s2) = &CaseSummary::parse_file(casename, &resdir.join(file), config) {
-            summary = summary.merge(s2);
-        }
+    if cancel.is_cancelled() {
+        return None;
     }
 
-    summary
+    let summary = CaseSummary::parse_case(casename, resdir, config, result);
+    let summary = compare::apply(summary, casefile, resdir, config.compare.as_ref()).await;
+    Some(scorer::apply(summary, casefile, resdir, config.scorer.as_ref()).await)
 }
 
 async fn local_request(
@@ -121,19 +209,28 @@ async fn local_request(
     config: &Config,
     casefile: &Path,
     resdir: &&Path,
+    cancel: &Cancel,
     msg: &mut String,
-) -> Result<(), ()> {
+) -> Result<Option<ExitStatus>, ()> {
     writeln!(msg, "[CLI] ファイルの送信").unwrap();
     for t in &config.local.send {
-        if let Err(e) = fs::copy(
-            &t.from
-                .to_string_lossy()
-                .replace("$casefile", &casefile.to_string_lossy()),
-            temp_dir.path().join(&t.to),
-        ) {
+        let from = t
+            .from
+            .to_string_lossy()
+            .replace("$casefile", &casefile.to_string_lossy());
+        let copied = t.mode().and_then(|mode| {
+            transfer::copy(
+                Path::new(""),
+                Path::new(&from),
+                temp_dir.path(),
+                &t.to,
+                mode,
+            )
+        });
+        if let Err(e) = copied {
             writeln!(
                 msg,
-                "[CLI] [IE] ファイル {} を {} にコピーできません{e:?}",
+                "[CLI] [IE] ファイル {} を {} にコピーできません: {e:#}",
                 t.from.display(),
                 temp_dir.path().join(&t.to).display()
             )
@@ -143,16 +240,42 @@ async fn local_request(
     }
 
     writeln!(msg, "[CLI] コマンドの実行").unwrap();
-    if let Err(e) = exec_start_sh(temp_dir) {
-        writeln!(msg, "[CLI] [IE] start.sh を正常に実行できません: {e:?}").unwrap();
-    }
+    let exit_status = if let Some(interactive) = &config.interactive {
+        match interactive::run(interactive, temp_dir.path(), &config.env, cancel).await {
+            Ok(outcome) => {
+                writeln!(msg, "[CLI] 対話の終了: {outcome}").unwrap();
+                if let Some(verdict) = outcome.verdict() {
+                    writeln!(msg, "[CLI] {verdict}").unwrap();
+                }
+                Some(outcome.solution)
+            }
+            Err(e) => {
+                writeln!(msg, "[CLI] [IE] 対話型の実行ができません: {e:#}").unwrap();
+                None
+            }
+        }
+    } else {
+        match exec_start_sh(temp_dir, config, cancel).await {
+            Ok(status) => {
+                writeln!(msg, "[CLI] start.sh の終了: {status}").unwrap();
+                Some(status)
+            }
+            Err(e) => {
+                writeln!(msg, "[CLI] [IE] start.sh を正常に実行できません: {e:#}").unwrap();
+                None
+            }
+        }
+    };
 
     writeln!(msg, "[CLI] ファイルの回収").unwrap();
     for t in &config.local.collect {
-        if let Err(e) = fs::copy(temp_dir.path().join(&t.from), resdir.join(&t.to)) {
+        let copied = t
+            .mode()
+            .and_then(|mode| transfer::copy(temp_dir.path(), &t.from, resdir, &t.to, mode));
+        if let Err(e) = copied {
             writeln!(
                 msg,
-                "[CLI] ファイル {} を {} にコピーできません{e:?}",
+                "[CLI] ファイル {} を {} にコピーできません: {e:#}",
                 temp_dir.path().join(&t.from).display(),
                 resdir.join(&t.to).display()
             )
@@ -161,17 +284,20 @@ async fn local_request(
     }
 
     writeln!(msg, "[CLI] 実行完了").unwrap();
-    Ok(())
+    Ok(exit_status)
 }
 
-fn exec_start_sh(temp_dir: &TempDir) -> Result<()> {
+/// 異常終了はエラーにせず、終了の仕方を返す
+async fn exec_start_sh(temp_dir: &TempDir, config: &Config, cancel: &Cancel) -> Result<ExitStatus> {
     let mut outfile = File::create(temp_dir.path().join("start_out.txt"))
         .context("start_out.txt が作成できません")?;
     let mut errfile = File::create(temp_dir.path().join("start_err.txt"))
         .context("start_err.txt が作成できません")?;
 
-    let output = Command::new("bash")
+    let mut command = Command::new("bash");
+    command
         .current_dir(temp_dir)
+        .envs(&config.env)
         .arg(temp_dir.path().join("start.sh"))
         .stdout(Stdio::from(
             outfile
@@ -182,10 +308,28 @@ fn exec_start_sh(temp_dir: &TempDir) -> Result<()> {
             errfile
                 .try_clone()
                 .context("start_err.txt に接続できません")?,
-        ))
+        ));
+    #[cfg(unix)]
+    command.process_group(0);
+
+    let child = tokio::process::Command::from(command)
+        .kill_on_drop(true)
         .spawn()
-        .context("プロセスが起動できません")?
-        .wait_with_output()
+        .context("プロセスが起動できません")?;
+    let pgid = child.id().context("プロセスIDが取得できません")?;
+    cancel::register_process_group(pgid);
+
+    let waited = tokio::select! {
+        output = child.wait_with_output() => Some(output),
+        _ = cancel.cancelled() => {
+            cancel::kill_process_group(pgid);
+            None
+        }
+    };
+    cancel::unregister_process_group(pgid);
+
+    let output = waited
+        .context("中断されました")?
         .context("bash の待機中にエラーが発生しました")?;
 
     let out_res = outfile
@@ -196,9 +340,9 @@ fn exec_start_sh(temp_dir: &TempDir) -> Result<()> {
         .write_all(&output.stderr)
         .context("start_err.txt に書き込めません");
 
-    ensure!(output.status.success(), "start.sh が異常終了しました");
-
     out_res
         .and(err_res)
-        .context("start.sh の出力が最後まで取得できませんでした")
+        .context("start.sh の出力が最後まで取得できませんでした")?;
+
+    Ok(output.status.into())
 }
@@ -0,0 +1,407 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{comma_sep_int, submission_state::SubmissionStateSingle, summary::FinalSummary};
+
+const HISTOGRAM_BINS: usize = 20;
+
+pub fn write_report(
+    subm_dir: &Path,
+    summary: &FinalSummary,
+    compare: Option<&FinalSummary>,
+) -> Result<PathBuf> {
+    let path = subm_dir.join("report.html");
+    let html = render(subm_dir, summary, compare);
+    fs::write(&path, html).with_context(|| format!("レポート {path:?} が書き込めません"))?;
+    Ok(path)
+}
+
+fn render(subm_dir: &Path, summary: &FinalSummary, compare: Option<&FinalSummary>) -> String {
+    let mut html = String::new();
+
+    writeln!(html, "<!DOCTYPE html>").unwrap();
+    writeln!(html, "<html lang=\"ja\">").unwrap();
+    writeln!(html, "<head>").unwrap();
+    writeln!(html, "<meta charset=\"utf-8\">").unwrap();
+    writeln!(html, "<title>提出@{}</title>", summary.subm_id).unwrap();
+    writeln!(html, "<style>{STYLE}</style>").unwrap();
+    writeln!(html, "</head>").unwrap();
+    writeln!(html, "<body>").unwrap();
+
+    writeln!(html, "<h1>提出@{}</h1>", summary.subm_id).unwrap();
+    writeln!(html, "<div class=\"summaries\">").unwrap();
+    writeln!(html, "<pre>{}</pre>", escape(&summary.to_string())).unwrap();
+    if let Some(other) = compare {
+        writeln!(html, "<pre>{}</pre>", escape(&other.to_string())).unwrap();
+    }
+    writeln!(html, "</div>").unwrap();
+
+    if let Some(other) = compare {
+        render_comparison(&mut html, summary, other);
+    }
+
+    writeln!(html, "<h2>スコア分布</h2>").unwrap();
+    render_histogram(&mut html, &summary.scores);
+
+    writeln!(html, "<h2>ケース</h2>").unwrap();
+    render_case_table(&mut html, subm_dir, summary, compare);
+
+    writeln!(html, "<script>{SCRIPT}</script>").unwrap();
+    writeln!(html, "</body>").unwrap();
+    writeln!(html, "</html>").unwrap();
+
+    html
+}
+
+fn render_comparison(html: &mut String, summary: &FinalSummary, other: &FinalSummary) {
+    let others: HashMap<_, _> = other.cases.iter().map(|c| (&c.name, c)).collect();
+    let (mut win, mut lose, mut draw) = (0, 0, 0);
+
+    for case in &summary.cases {
+        if let Some(o) = others.get(&case.name) {
+            match case.score.partial_cmp(&o.score) {
+                Some(std::cmp::Ordering::Greater) => win += 1,
+                Some(std::cmp::Ordering::Less) => lose += 1,
+                _ => draw += 1,
+            }
+        }
+    }
+
+    writeln!(
+        html,
+        "<h2>比較 (提出@{} vs 提出@{})</h2>",
+        summary.subm_id, other.subm_id
+    )
+    .unwrap();
+    writeln!(
+        html,
+        "<p>勝ち {win} / 負け {lose} / 引き分け {draw}（スコアの大小で判定）</p>"
+    )
+    .unwrap();
+}
+
+fn render_histogram(html: &mut String, scores: &[f64]) {
+    if scores.is_empty() {
+        writeln!(html, "<p>ケースがありません</p>").unwrap();
+        return;
+    }
+
+    let min = scores.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = scores.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let width = ((max - min) / HISTOGRAM_BINS as f64).max(f64::MIN_POSITIVE);
+
+    let mut bins = [0usize; HISTOGRAM_BINS];
+    for &s in scores {
+        let i = (((s - min) / width) as usize).min(HISTOGRAM_BINS - 1);
+        bins[i] += 1;
+    }
+    let peak = bins.iter().max().cloned().unwrap_or(1).max(1);
+
+    let (w, h, bar) = (640., 200., 640. / HISTOGRAM_BINS as f64);
+    writeln!(
+        html,
+        "<svg class=\"histogram\" viewBox=\"0 0 {w} {}\" width=\"{w}\" height=\"{}\">",
+        h + 20.,
+        h + 20.
+    )
+    .unwrap();
+    for (i, &count) in bins.iter().enumerate() {
+        let bh = h * count as f64 / peak as f64;
+        let lo = min + width * i as f64;
+        writeln!(
+            html,
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{bh:.1}\"><title>{} ~ {} pt: {count} ケース</title></rect>",
+            bar * i as f64 + 1.,
+            h - bh,
+            bar - 2.,
+            comma_sep_int(lo as i128),
+            comma_sep_int((lo + width) as i128),
+        )
+        .unwrap();
+    }
+    writeln!(
+        html,
+        "<text x=\"0\" y=\"{}\">{}</text><text x=\"{w}\" y=\"{}\" text-anchor=\"end\">{}</text>",
+        h + 16.,
+        comma_sep_int(min as i128),
+        h + 16.,
+        comma_sep_int(max as i128)
+    )
+    .unwrap();
+    writeln!(html, "</svg>").unwrap();
+}
+
+fn render_case_table(
+    html: &mut String,
+    subm_dir: &Path,
+    summary: &FinalSummary,
+    compare: Option<&FinalSummary>,
+) {
+    let others: HashMap<_, _> = compare
+        .map(|o| o.cases.iter().map(|c| (&c.name, c)).collect())
+        .unwrap_or_default();
+
+    writeln!(html, "<table class=\"sortable\">").unwrap();
+    write!(
+        html,
+        "<thead><tr><th>ケース</th><th>状態</th><th>時間 (ms)</th><th>スコア</th><th>割合 (%)</th>"
+    )
+    .unwrap();
+    if let Some(other) = compare {
+        write!(html, "<th>提出@{} スコア</th><th>差分</th>", other.subm_id).unwrap();
+    }
+    writeln!(html, "<th>可視化</th><th>ファイル</th></tr></thead>").unwrap();
+    writeln!(html, "<tbody>").unwrap();
+
+    let mut cases = summary.cases.clone();
+    cases.sort_by(|a, b| a.name.cmp(&b.name));
+
+    for case in &cases {
+        let casedir_name = format!("c_{}", case.name);
+        let state = SubmissionStateSingle::try_from(case.state)
+            .map(|single| single.to_string())
+            .unwrap_or("???".into());
+        let class = if case.state == SubmissionStateSingle::AC as u32 {
+            "ac"
+        } else {
+            "non-ac"
+        };
+
+        write!(html, "<tr class=\"{class}\">").unwrap();
+        write!(html, "<td>{}</td>", escape(&case.name)).unwrap();
+        write!(html, "<td>{state}</td>").unwrap();
+        write!(
+            html,
+            "<td data-value=\"{}\">{:.0}</td>",
+            case.time,
+            case.time * 1000.
+        )
+        .unwrap();
+        write!(
+            html,
+            "<td data-value=\"{}\">{}</td>",
+            case.score,
+            comma_sep_int(case.score.round() as i128)
+        )
+        .unwrap();
+        write!(
+            html,
+            "<td data-value=\"{}\">{:.2}</td>",
+            case.rate,
+            case.rate * 100.
+        )
+        .unwrap();
+
+        if compare.is_some() {
+            match others.get(&case.name) {
+                Some(o) => {
+                    let diff = case.score - o.score;
+                    let diff_class = if diff > 0. {
+                        "better"
+                    } else if diff < 0. {
+                        "worse"
+                    } else {
+                        ""
+                    };
+                    write!(
+                        html,
+                        "<td data-value=\"{}\">{}</td><td data-value=\"{diff}\" class=\"{diff_class}\">{}</td>",
+                        o.score,
+                        comma_sep_int(o.score.round() as i128),
+                        comma_sep_int(diff.round() as i128)
+                    )
+                    .unwrap();
+                }
+                None => {
+                    write!(html, "<td data-value=\"\">-</td><td data-value=\"\">-</td>").unwrap()
+                }
+            }
+        }
+
+        let files = list_files(&subm_dir.join(&casedir_name));
+
+        write!(html, "<td>").unwrap();
+        for svg in files.iter().filter(|f| f.ends_with(".svg")) {
+            let href = format!("{casedir_name}/{svg}");
+            write!(
+                html,
+                "<a href=\"{0}\"><img class=\"thumb\" src=\"{0}\" loading=\"lazy\" alt=\"{1}\"></a>",
+                escape(&href),
+                escape(svg)
+            )
+            .unwrap();
+        }
+        write!(html, "</td>").unwrap();
+
+        write!(html, "<td>").unwrap();
+        for file in &files {
+            write!(
+                html,
+                "<a href=\"{}\">{}</a> ",
+                escape(&format!("{casedir_name}/{file}")),
+                escape(file)
+            )
+            .unwrap();
+        }
+        writeln!(html, "</td></tr>").unwrap();
+    }
+
+    writeln!(html, "</tbody>").unwrap();
+    writeln!(html, "</table>").unwrap();
+}
+
+fn list_files(dir: &Path) -> Vec<String> {
+    let mut files: Vec<String> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            entry
+                .path()
+                .is_file()
+                .then(|| entry.file_name().to_string_lossy().into_owned())
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+fn escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&#39;"),
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+const STYLE: &str = r#"
+body { font-family: sans-serif; margin: 1em 2em; }
+.summaries { display: flex; gap: 2em; }
+pre { background: #f4f4f4; padding: 0.5em 1em; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 2px 6px; text-align: right; vertical-align: middle; }
+th { background: #eee; cursor: pointer; user-select: none; }
+th.asc::after { content: " ▲"; }
+th.desc::after { content: " ▼"; }
+tr.non-ac td { color: #c00; }
+td.better { color: #080; }
+td.worse { color: #c00; }
+img.thumb { max-width: 120px; max-height: 120px; border: 1px solid #ddd; }
+svg.histogram rect { fill: #4a7ebb; }
+svg.histogram text { font-size: 12px; }
+"#;
+
+const SCRIPT: &str = r#"
+document.querySelectorAll("table.sortable").forEach((table) => {
+  const headers = table.querySelectorAll("th");
+  headers.forEach((th, col) => {
+    th.addEventListener("click", () => {
+      const asc = !th.classList.contains("asc");
+      headers.forEach((h) => h.classList.remove("asc", "desc"));
+      th.classList.add(asc ? "asc" : "desc");
+      const body = table.tBodies[0];
+      const key = (row) => {
+        const cell = row.cells[col];
+        const v = cell.dataset.value;
+        if (v !== undefined && v !== "" && !isNaN(Number(v))) return Number(v);
+        return cell.textContent;
+      };
+      const rows = Array.from(body.rows);
+      rows.sort((a, b) => {
+        const x = key(a), y = key(b);
+        const c = typeof x === "number" && typeof y === "number" ? x - y : String(x).localeCompare(String(y), undefined, { numeric: true });
+        return asc ? c : -c;
+      });
+      rows.forEach((r) => body.appendChild(r));
+    });
+  });
+});
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::summary::CaseSummary;
+    use SubmissionStateSingle::*;
+
+    fn submission(subm_id: u32, cases: &[(&str, SubmissionStateSingle, f64)]) -> FinalSummary {
+        let mut summary = FinalSummary::zero(subm_id);
+        for &(name, state, score) in cases {
+            summary.next_case(&CaseSummary {
+                score,
+                ..CaseSummary::zero(name, state as u32)
+            });
+        }
+        summary
+    }
+
+    #[test]
+    fn renders_cases_sorted_with_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let subm_dir = temp.path();
+        fs::create_dir(subm_dir.join("c_0001")).unwrap();
+        fs::write(subm_dir.join("c_0001/vis.svg"), "<svg/>").unwrap();
+        fs::write(subm_dir.join("c_0001/out.txt"), "1").unwrap();
+
+        let summary = submission(3, &[("0001", AC, 1500.), ("0000", WA, 0.)]);
+        let html = render(subm_dir, &summary, None);
+
+        assert!(html.contains("<title>提出@3</title>"));
+        let (first, second) = (
+            html.find("<td>0000</td>").unwrap(),
+            html.find("<td>0001</td>").unwrap(),
+        );
+        assert!(first < second);
+        assert!(html.contains("<tr class=\"non-ac\"><td>0000</td><td>WA</td>"));
+        assert!(html.contains("<td data-value=\"1500\">1,500</td>"));
+        assert!(html.contains("<img class=\"thumb\" src=\"c_0001/vis.svg\""));
+        assert!(html.contains("<a href=\"c_0001/out.txt\">out.txt</a>"));
+        // ケースのフォルダがなければファイルの欄は空
+        assert!(html.contains(">0.00</td><td></td><td></td></tr>"));
+        assert!(!html.contains("比較"));
+        assert_eq!(html.matches("<rect ").count(), HISTOGRAM_BINS);
+    }
+
+    #[test]
+    fn renders_comparison_with_other_submission() {
+        let temp = tempfile::tempdir().unwrap();
+        let summary = submission(
+            5,
+            &[("a", AC, 10.), ("b", AC, 5.), ("c", AC, 7.), ("d", AC, 1.)],
+        );
+        let other = submission(4, &[("a", AC, 8.), ("b", AC, 8.), ("c", AC, 7.)]);
+        let html = render(temp.path(), &summary, Some(&other));
+
+        assert!(html.contains("<h2>比較 (提出@5 vs 提出@4)</h2>"));
+        assert!(html.contains("勝ち 1 / 負け 1 / 引き分け 1"));
+        assert!(html.contains("<th>提出@4 スコア</th>"));
+        assert!(html.contains("class=\"better\">2</td>"));
+        assert!(html.contains("class=\"worse\">-3</td>"));
+        // 相手にないケースは比べない
+        assert!(html.contains("<td data-value=\"\">-</td>"));
+    }
+
+    #[test]
+    fn renders_empty_histogram_and_escapes() {
+        let temp = tempfile::tempdir().unwrap();
+        let html = render(temp.path(), &FinalSummary::zero(1), None);
+        assert!(html.contains("<p>ケースがありません</p>"));
+        assert_eq!(
+            escape("<a href=\"x\">&'"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;"
+        );
+    }
+}
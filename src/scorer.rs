@@ -0,0 +1,186 @@
+//! 回収した出力を手元で採点する。解答を実行し直さずに judge rescore で採点し直せる
+
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::{fs, io::Write as _, path::Path, process::Stdio};
+
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::{
+    config::ScorerConfig,
+    submission_state::SubmissionStateSingle::{self, *},
+    summary::{CaseSummary, Rate, Score, Time},
+};
+
+/// 採点コマンドの標準出力
+pub const SCORER_OUT: &str = "scorer_out.txt";
+/// 採点コマンドの標準エラー出力。採点できなかった理由もここに書く
+pub const SCORER_ERR: &str = "scorer_err.txt";
+
+/// 採点コマンドが標準出力の最後の行に書く JSON 。書かなかった項目はそのまま
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Verdict {
+    #[serde(default)]
+    pub state: Option<SubmissionStateSingle>,
+    #[serde(default)]
+    pub score: Option<Score>,
+    #[serde(default)]
+    pub rate: Option<Rate>,
+    #[serde(default)]
+    pub time: Option<Time>,
+}
+
+impl Verdict {
+    pub fn parse(stdout: &str) -> Result<Self> {
+        let line = stdout
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .context("採点コマンドが何も出力しませんでした")?;
+        serde_json::from_str(line)
+            .with_context(|| format!("採点結果が JSON ではありません: {line}"))
+    }
+}
+
+/// 設定があれば採点して、ケースの結果に反映する。
+/// 実行の時点で AC でなければ採点しない。解答の出力がなければ WA 、採点コマンドが動かなければ IE
+pub async fn apply(
+    summary: CaseSummary,
+    casefile: &Path,
+    casedir: &Path,
+    scorer: Option<&ScorerConfig>,
+) -> CaseSummary {
+    let Some(scorer) = scorer else {
+        return summary;
+    };
+
+    // TLE や RE の出力を採点しても、状態は変わらないうえ IE で隠れてしまう
+    if summary.state != AC as u32 {
+        return summary;
+    }
+
+    let output = casedir.join(&scorer.output);
+    if !output.is_file() {
+        let _ = fs::remove_file(casedir.join(SCORER_OUT));
+        let written = fs::write(
+            casedir.join(SCORER_ERR),
+            format!("[CLI] 解答の出力 {output:?} がないので採点しません\n"),
+        );
+        if let Err(e) = written {
+            println!("{SCORER_ERR} に書き込めません: {e:?}");
+        }
+        return CaseSummary {
+            state: summary.state | WA as u32,
+            ..summary
+        };
+    }
+
+    match run(scorer, casefile, &output, &summary.name, casedir).await {
+        Ok(verdict) => summary.with_verdict(&verdict),
+        Err(e) => {
+            let appended = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(casedir.join(SCORER_ERR))
+                .and_then(|mut file| writeln!(file, "[CLI] [IE] 採点できません: {e:#}"));
+            if let Err(e) = appended {
+                println!("{SCORER_ERR} に書き込めません: {e:?}");
+            }
+            CaseSummary {
+                state: summary.state | IE as u32,
+                ..summary
+            }
+        }
+    }
+}
+
+async fn run(
+    scorer: &ScorerConfig,
+    casefile: &Path,
+    output: &Path,
+    casename: &str,
+    casedir: &Path,
+) -> Result<Verdict> {
+    let mut command = Command::new("bash");
+    command
+        .arg("-c")
+        .arg(format!("{} \"$@\"", scorer.command))
+        .arg("scorer")
+        .arg(casefile)
+        .arg(output);
+    if let Some(expected) = &scorer.expected {
+        command.arg(expected.replace("$casename", casename));
+    }
+
+    let result = command
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("採点コマンドが起動できません")?;
+
+    fs::write(casedir.join(SCORER_OUT), &result.stdout)
+        .with_context(|| format!("{SCORER_OUT} が保存できません"))?;
+    fs::write(casedir.join(SCORER_ERR), &result.stderr)
+        .with_context(|| format!("{SCORER_ERR} が保存できません"))?;
+
+    ensure!(
+        result.status.success(),
+        "採点コマンドが異常終了しました: {}",
+        crate::exit_status::ExitStatus::from(result.status)
+    );
+    Verdict::parse(&String::from_utf8_lossy(&result.stdout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scorer(command: &str) -> ScorerConfig {
+        ScorerConfig {
+            command: command.into(),
+            output: "out.txt".into(),
+            expected: None,
+        }
+    }
+
+    async fn apply_in(dir: &Path, state: SubmissionStateSingle, command: &str) -> CaseSummary {
+        let summary = CaseSummary::zero("0000", state as u32);
+        apply(summary, &dir.join("0000.in"), dir, Some(&scorer(command))).await
+    }
+
+    #[test]
+    fn verdict_reads_last_line() {
+        let verdict = Verdict::parse("log\n{\"state\": \"WA\", \"score\": 3}\n\n").unwrap();
+        assert_eq!(verdict.state, Some(WA));
+        assert_eq!(verdict.score, Some(3.));
+        assert_eq!(verdict.rate, None);
+        assert!(Verdict::parse("\n").is_err());
+        assert!(Verdict::parse("score 3").is_err());
+    }
+
+    #[tokio::test]
+    async fn scores_only_accepted_cases() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        fs::write(dir.join("out.txt"), "1\n").unwrap();
+
+        let summary = apply_in(dir, AC, "f() { echo '{\"score\": 5}'; }; f").await;
+        assert_eq!((summary.state, summary.score), (AC as u32, 5.));
+        let summary = apply_in(dir, TLE, "f() { echo '{\"score\": 5}'; }; f").await;
+        assert_eq!((summary.state, summary.score), (TLE as u32, 0.));
+    }
+
+    #[tokio::test]
+    async fn missing_output_is_wrong_and_broken_scorer_is_internal() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        assert_eq!(apply_in(dir, AC, "true").await.state, WA as u32);
+
+        fs::write(dir.join("out.txt"), "1\n").unwrap();
+        assert_eq!(apply_in(dir, AC, "exit 3").await.state, IE as u32);
+        let err = fs::read_to_string(dir.join(SCORER_ERR)).unwrap();
+        assert!(err.contains("[IE] 採点できません"), "{err}");
+    }
+}
@@ -0,0 +1,349 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::{
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{CompareConfig, Config, FileTransferConfig, ParseResultConfig, ScorerConfig},
+    sweep, transfer,
+};
+
+pub const CONFIG_SNAPSHOT: &str = "config.yaml";
+pub const CASE_LIST: &str = "cases.txt";
+/// 採点し直したときの設定。実行時の config.yaml はそのまま残す
+pub const RESCORE: &str = "rescore.yaml";
+
+/// 1回の実行で作る提出。複数の解答を比べるときは解答ごとに1つ
+#[derive(Debug, Clone)]
+pub struct Submission {
+    /// 比べる解答の名前
+    pub label: Option<String>,
+    pub subm_dir: PathBuf,
+    pub config: Config,
+}
+
+impl Submission {
+    /// pre でビルドしたファイルを記録するので、 pre の後に呼ぶ。再開したときは最初の実行の来歴を残す
+    pub fn save_provenance(&self, mode: &str, send: &[FileTransferConfig], casefiles: &[PathBuf]) {
+        if self.subm_dir.join(PROVENANCE).exists() {
+            return;
+        }
+        if let Err(e) = Provenance::collect(mode, send, casefiles).save(&self.subm_dir) {
+            println!("来歴が保存できません: {e:?}");
+        }
+    }
+}
+
+/// 設定から作る提出。 solutions や sweep があれば、解答や組み合わせごとに続き番号の提出IDで作る
+pub fn submissions(config: &Config, seed: u64) -> Result<Vec<Submission>> {
+    if let Some(sweep) = &config.sweep {
+        ensure!(
+            config.solutions.is_empty(),
+            "solutions と sweep は同時に使えません"
+        );
+        let combinations = sweep::combinations(sweep, seed)?;
+        return Ok(combinations
+            .iter()
+            .zip(config.subm_id..)
+            .map(|(params, subm_id)| Submission {
+                label: Some(sweep::label(params)),
+                subm_dir: subm_dir(subm_id),
+                config: config.for_params(params, subm_id),
+            })
+            .collect());
+    }
+
+    if config.solutions.is_empty() {
+        return Ok(vec![Submission {
+            label: None,
+            subm_dir: subm_dir(config.subm_id),
+            config: config.clone(),
+        }]);
+    }
+
+    Ok(config
+        .solutions
+        .iter()
+        .zip(config.subm_id..)
+        .map(|(solution, subm_id)| Submission {
+            label: Some(solution.name.clone()),
+            subm_dir: subm_dir(subm_id),
+            config: config.for_solution(solution, subm_id),
+        })
+        .collect())
+}
+
+pub fn subm_dir(subm_id: u32) -> PathBuf {
+    PathBuf::from(format!("results/s_{subm_id:0>4}"))
+}
+
+pub fn case_name(casefile: &Path) -> String {
+    casefile
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn case_dir(subm_dir: &Path, casename: &str) -> PathBuf {
+    subm_dir.join(format!("c_{casename}"))
+}
+
+/// 結果フォルダにあるケースのフォルダを、ケース名の順に並べる
+pub fn case_dirs(subm_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut casedirs: Vec<_> = fs::read_dir(subm_dir)
+        .with_context(|| format!("結果フォルダ {subm_dir:?} が読み込めません"))?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let name = path.file_name()?.to_str()?.strip_prefix("c_")?.to_owned();
+            path.is_dir().then_some((name, path))
+        })
+        .collect();
+    casedirs.sort();
+    Ok(casedirs)
+}
+
+/// 再開できるように、実行時の設定とテストケースの一覧を結果フォルダに保存する
+pub fn save_snapshot(subm_dir: &Path, config: &Config, casefiles: &[PathBuf]) -> Result<()> {
+    let yaml = serde_yaml::to_string(config).context("設定を YAML に変換できません")?;
+    fs::write(subm_dir.join(CONFIG_SNAPSHOT), yaml)
+        .with_context(|| format!("{CONFIG_SNAPSHOT} が保存できません"))?;
+
+    let list: String = casefiles
+        .iter()
+        .map(|p| format!("{}\n", p.display()))
+        .collect();
+    fs::write(subm_dir.join(CASE_LIST), list)
+        .with_context(|| format!("{CASE_LIST} が保存できません"))
+}
+
+pub fn load_snapshot(subm_dir: &Path) -> Result<(Config, Vec<PathBuf>)> {
+    let yaml = fs::read_to_string(subm_dir.join(CONFIG_SNAPSHOT))
+        .with_context(|| format!("{subm_dir:?} に {CONFIG_SNAPSHOT} がありません"))?;
+    let config: Config =
+        serde_yaml::from_str(&yaml).context("保存された設定ファイルが誤っています")?;
+
+    let casefiles = fs::read_to_string(subm_dir.join(CASE_LIST))
+        .with_context(|| format!("{subm_dir:?} に {CASE_LIST} がありません"))?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect();
+
+    Ok((config, casefiles))
+}
+
+/// 採点し直しに使った設定
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Rescore {
+    pub rescored_at: String,
+    pub parse_result: ParseResultConfig,
+    pub compare: Option<CompareConfig>,
+    pub scorer: Option<ScorerConfig>,
+}
+
+impl Rescore {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            rescored_at: crate::now(),
+            parse_result: config.parse_result.clone(),
+            compare: config.compare.clone(),
+            scorer: config.scorer.clone(),
+        }
+    }
+
+    pub fn save(&self, subm_dir: &Path) -> Result<()> {
+        let yaml = serde_yaml::to_string(self).context("設定を YAML に変換できません")?;
+        fs::write(subm_dir.join(RESCORE), yaml)
+            .with_context(|| format!("{RESCORE} が保存できません"))
+    }
+}
+
+pub const PROVENANCE: &str = "provenance.yaml";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Provenance {
+    pub created_at: String,
+    pub judge_version: String,
+    pub mode: String,
+    pub git: Option<GitState>,
+    pub files: Vec<FileHash>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct GitState {
+    pub head: String,
+    pub dirty: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct FileHash {
+    pub path: PathBuf,
+    pub sha256: Option<String>,
+    /// $casefile を含む送信ファイル。ケースごとにあるので、表示ではまとめる
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub case: bool,
+}
+
+impl Provenance {
+    /// 送信するファイルのハッシュと作業ディレクトリの git の状態を記録する。
+    /// ディレクトリや glob パターンは中のファイルごとに、 $casefile を含むものはケースごとに記録する
+    pub fn collect(mode: &str, send: &[FileTransferConfig], casefiles: &[PathBuf]) -> Self {
+        let mut files = vec![];
+        for t in send {
+            let from = t.from.to_string_lossy();
+            if !from.contains("$casefile") {
+                files.extend(hash_files(&t.from, false));
+                continue;
+            }
+            for casefile in casefiles {
+                let from = from.replace("$casefile", &casefile.to_string_lossy());
+                files.extend(hash_files(Path::new(&from), true));
+            }
+        }
+
+        Self {
+            created_at: crate::now(),
+            judge_version: env!("CARGO_PKG_VERSION").into(),
+            mode: mode.into(),
+            git: git_state(),
+            files,
+        }
+    }
+
+    pub fn save(&self, subm_dir: &Path) -> Result<()> {
+        let yaml = serde_yaml::to_string(self).context("来歴を YAML に変換できません")?;
+        fs::write(subm_dir.join(PROVENANCE), yaml)
+            .with_context(|| format!("{PROVENANCE} が保存できません"))
+    }
+
+    pub fn load(subm_dir: &Path) -> Result<Self> {
+        let yaml = fs::read_to_string(subm_dir.join(PROVENANCE))
+            .with_context(|| format!("{subm_dir:?} に {PROVENANCE} がありません"))?;
+        serde_yaml::from_str(&yaml).context("保存された来歴が誤っています")
+    }
+}
+
+impl Display for Provenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "作成: {}", self.created_at)?;
+        writeln!(f, "judge: {}", self.judge_version)?;
+        writeln!(f, "実行: {}", self.mode)?;
+        match &self.git {
+            Some(git) if git.dirty => writeln!(f, "git: {} (未コミットの変更あり)", git.head)?,
+            Some(git) => writeln!(f, "git: {}", git.head)?,
+            None => writeln!(f, "git: -")?,
+        }
+        writeln!(f, "送信ファイル:")?;
+        for file in self.files.iter().filter(|file| !file.case) {
+            writeln!(
+                f,
+                "  {} {}",
+                file.sha256.as_deref().unwrap_or("(読み込めません)"),
+                file.path.display()
+            )?;
+        }
+        let cases = self.files.iter().filter(|file| file.case).count();
+        if cases > 0 {
+            writeln!(
+                f,
+                "  ケースごとのファイル {cases} 件（{PROVENANCE} に記録）"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// from を展開した各ファイルのハッシュ。展開できなければ from を読めなかったと記録する
+fn hash_files(from: &Path, case: bool) -> Vec<FileHash> {
+    match transfer::resolve(Path::new(""), from) {
+        Ok(files) => files
+            .into_iter()
+            .map(|(path, _)| FileHash {
+                sha256: sha256_file(&path).ok(),
+                path,
+                case,
+            })
+            .collect(),
+        Err(_) => vec![FileHash {
+            path: from.into(),
+            sha256: None,
+            case,
+        }],
+    }
+}
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let data = fs::read(path).with_context(|| format!("ファイル {path:?} が読み取れません"))?;
+    Ok(crate::sha256(&data))
+}
+
+fn git_state() -> Option<GitState> {
+    let head = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let status = Command::new("git")
+        .args(["status", "--porcelain"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+
+    Some(GitState {
+        head: String::from_utf8_lossy(&head.stdout).trim().into(),
+        dirty: !status.stdout.is_empty(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(from: &Path) -> FileTransferConfig {
+        serde_yaml::from_str(&format!("{{ from: {:?}, to: x }}", from)).unwrap()
+    }
+
+    #[test]
+    fn provenance_hashes_directories_and_case_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        fs::create_dir_all(dir.join("bin/data")).unwrap();
+        fs::write(dir.join("bin/main"), "main").unwrap();
+        fs::write(dir.join("bin/data/a.txt"), "a").unwrap();
+        fs::write(dir.join("0000.txt"), "case").unwrap();
+
+        let sends = [
+            send(&dir.join("bin")),
+            send(&dir.join("bin/*.txt")),
+            send(Path::new("$casefile")),
+        ];
+        let provenance = Provenance::collect("local", &sends, &[dir.join("0000.txt")]);
+
+        let hashes: Vec<_> = provenance
+            .files
+            .iter()
+            .map(|file| (file.path.strip_prefix(dir).unwrap(), file.case))
+            .collect();
+        assert_eq!(
+            hashes,
+            [
+                (Path::new("bin/data/a.txt"), false),
+                (Path::new("bin/main"), false),
+                (Path::new("bin/*.txt"), false),
+                (Path::new("0000.txt"), true),
+            ]
+        );
+        assert_eq!(provenance.files[1].sha256, Some(crate::sha256(b"main")));
+        assert_eq!(provenance.files[2].sha256, None);
+        assert!(provenance.to_string().contains("ケースごとのファイル 1 件"));
+    }
+}
@@ -1,13 +1,14 @@
 use std::fmt::Display;
 
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use SubmissionStateSingle::*;
 
 use crate::config::Config;
 
 pub type SubmissionState = u32;
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default, Serialize, Deserialize)]
 pub enum SubmissionStateSingle {
     IE = 1 << 7,
     CE = 1 << 6,
@@ -17,6 +18,7 @@ pub enum SubmissionStateSingle {
     WA = 1 << 2,
     TLE = 1 << 1,
     MLE = 1 << 0,
+    #[default]
     AC = 0,
 }
 
@@ -47,67 +49,61 @@ impl Display for SubmissionStateSingle {
     }
 }
 
-impl Default for SubmissionStateSingle {
-    fn default() -> Self {
-        AC
-    }
-}
-
 pub fn parse_state(text: &str, config: &Config) -> SubmissionState {
     let mut state = AC as u32;
 
     if Regex::new(&config.parse_result.force_ac_regex)
         .unwrap()
-        .is_match(&text)
+        .is_match(text)
     {
         return AC as u32;
     }
 
     if Regex::new(&config.parse_result.ie_regex)
         .unwrap()
-        .is_match(&text)
+        .is_match(text)
     {
         state |= IE as u32;
     }
     if Regex::new(&config.parse_result.ce_regex)
         .unwrap()
-        .is_match(&text)
+        .is_match(text)
     {
         state |= CE as u32;
     }
     if Regex::new(&config.parse_result.re_regex)
         .unwrap()
-        .is_match(&text)
+        .is_match(text)
     {
         state |= RE as u32;
     }
     if Regex::new(&config.parse_result.qle_regex)
         .unwrap()
-        .is_match(&text)
+        .is_match(text)
     {
         state |= QLE as u32;
     }
     if Regex::new(&config.parse_result.ole_regex)
         .unwrap()
-        .is_match(&text)
+        .is_match(text)
     {
         state |= OLE as u32;
     }
     if Regex::new(&config.parse_result.wa_regex)
         .unwrap()
-        .is_match(&text)
+        .is_match(text)
     {
         state |= WA as u32;
     }
     if Regex::new(&config.parse_result.tle_regex)
         .unwrap()
-        .is_match(&text)
+        .is_match(text)
     {
         state |= TLE as u32;
     }
     if Regex::new(&config.parse_result.mle_regex)
         .unwrap()
-        .is_match(&text)
+        .is_match(text)
     {
         state |= MLE as u32;
     }
@@ -1,12 +1,17 @@
 #[allow(unused_imports)]
 use anyhow::{anyhow, bail, ensure, Context, Result};
-use std::{cmp::Ordering, fmt::Display, fs, path::Path};
+use std::{cmp::Ordering, collections::BTreeSet, fmt::Display, fs, path::Path};
 
+use console::{pad_str, Alignment};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    comma_sep_int,
-    config::Config,
+    comma_sep_int, compare,
+    config::{Config, LambdaPriceConfig},
+    exit_status::ExitStatus,
+    scorer::Verdict,
+    submission,
     submission_state::SubmissionStateSingle,
     submission_state::{self, SubmissionState, SubmissionStateSingle::*},
 };
@@ -16,19 +21,61 @@ pub type Time = f64;
 pub type Score = f64;
 pub type Rate = f64;
 
-#[derive(Clone, Debug)]
+pub const CASE_RESULT: &str = "result.json";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CaseSummary {
     pub name: String,
     pub state: SubmissionState,
     pub time: Time,
     pub score: Score,
     pub rate: Rate,
+    /// AWS Lambda で実行したときだけ入る
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub billing: Option<Billing>,
+    /// start.sh の終了の仕方。起動できなかったときや古い bootstrap では入らない
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_status: Option<ExitStatus>,
+}
+
+/// AWS Lambda で課金の対象になった時間とメモリ
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Billing {
+    pub invocations: u32,
+    pub billed_ms: f64,
+    /// 呼び出しごとのメモリ × 時間の合計
+    pub gb_seconds: f64,
+    /// 関数 URL 経由など、ログから読めずに手元で測った時間を含む
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub estimated: bool,
+}
+
+impl Billing {
+    pub fn add_invocation(&mut self, billed_ms: f64, memory_mb: u32, estimated: bool) {
+        self.invocations += 1;
+        self.billed_ms += billed_ms;
+        self.gb_seconds += billed_ms / 1000. * memory_mb as f64 / 1024.;
+        self.estimated |= estimated;
+    }
+
+    pub fn add(&self, other: &Self) -> Self {
+        Self {
+            invocations: self.invocations + other.invocations,
+            billed_ms: self.billed_ms + other.billed_ms,
+            gb_seconds: self.gb_seconds + other.gb_seconds,
+            estimated: self.estimated || other.estimated,
+        }
+    }
+
+    pub fn cost(&self, price: &LambdaPriceConfig) -> f64 {
+        self.gb_seconds * price.per_gb_second + self.invocations as f64 * price.per_request
+    }
 }
 
 impl Display for CaseSummary {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let state_str = SubmissionStateSingle::try_from(self.state)
-            .and_then(|single| core::result::Result::Ok(single.to_string()))
+            .map(|single| single.to_string())
             .unwrap_or("???".into());
 
         let score_comma = comma_sep_int(self.score.round() as i128);
@@ -53,6 +100,8 @@ impl CaseSummary {
             time: 0.,
             score: 0.,
             rate: 0.,
+            billing: None,
+            exit_status: None,
         }
     }
 
@@ -63,22 +112,95 @@ impl CaseSummary {
             time: self.time.max(other.time),
             score: self.score.max(other.score),
             rate: self.rate.max(other.rate),
+            billing: self.billing.clone().or_else(|| other.billing.clone()),
+            exit_status: self
+                .exit_status
+                .clone()
+                .or_else(|| other.exit_status.clone()),
         }
     }
 
     pub fn parse_file(name: &str, path: &Path, config: &Config) -> Result<Self> {
-        let text = fs::read_to_string(path)?;
+        // judge が書いた比較の結果は、比べ直したときに前の結果を読まないように除く
+        let text = compare::without_result(&fs::read_to_string(path)?);
         Ok(Self {
             name: name.into(),
             state: submission_state::parse_state(&text, config),
             time: parse_time(config, &text).unwrap_or(0.),
             score: parse_score(config, &text).unwrap_or(0.),
             rate: parse_rate(config, &text).unwrap_or(0.),
+            billing: None,
+            exit_status: None,
         })
     }
+
+    /// 回収したファイルからケースの結果を読む。実行できなかったときは executed が Err
+    pub fn parse_case(
+        name: &str,
+        casedir: &Path,
+        config: &Config,
+        executed: Result<Option<ExitStatus>, ()>,
+    ) -> Self {
+        let (mut summary, exit_status) = match executed {
+            Ok(exit_status) => (Self::zero(name, AC as u32), exit_status),
+            Err(()) => (Self::zero(name, IE as u32), None),
+        };
+
+        for file in &config.parse_result.files {
+            if let Ok(s2) = &Self::parse_file(name, &casedir.join(file), config) {
+                summary = summary.merge(s2);
+            }
+        }
+
+        summary.with_exit_status(exit_status, config)
+    }
+
+    /// 採点コマンドの結果で置き換える。状態は実行時のものと合わせる
+    pub fn with_verdict(mut self, verdict: &Verdict) -> Self {
+        if let Some(state) = verdict.state {
+            self.state |= state as u32;
+        }
+        self.score = verdict.score.unwrap_or(self.score);
+        self.rate = verdict.rate.unwrap_or(self.rate);
+        self.time = verdict.time.unwrap_or(self.time);
+        self
+    }
+
+    /// start.sh の終了の仕方を記録し、設定に従って状態に反映する。
+    /// 出力から実行時間が読めなかったときは、 judge が測った時間を使う
+    pub fn with_exit_status(mut self, exit_status: Option<ExitStatus>, config: &Config) -> Self {
+        if let Some(status) = &exit_status {
+            self.state |= config.parse_result.exit_status.state(status);
+            if self.time == 0. {
+                self.time = status.time.unwrap_or(0.);
+            }
+        }
+        self.exit_status = exit_status;
+        self
+    }
+
+    /// 実行中の表示。複数の解答を比べるときは解答の名前を付ける
+    pub fn labeled(&self, label: Option<&str>) -> String {
+        match label {
+            Some(label) => format!("[{label}] {self}"),
+            None => self.to_string(),
+        }
+    }
+
+    pub fn save_result(&self, casedir: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self)?;
+        fs::write(casedir.join(CASE_RESULT), json)
+            .with_context(|| format!("{casedir:?} に {CASE_RESULT} が保存できません"))
+    }
+
+    pub fn load_result(casedir: &Path) -> Result<Self> {
+        let json = fs::read(casedir.join(CASE_RESULT))
+            .with_context(|| format!("{casedir:?} に {CASE_RESULT} がありません"))?;
+        serde_json::from_slice(&json).context("保存されたケースの結果が誤っています")
+    }
 }
 
-fn parse_time(config: &Config, text: &String) -> Result<Time> {
+fn parse_time(config: &Config, text: &str) -> Result<Time> {
     Ok(Regex::new(&config.parse_result.time_regex)?
         .captures(text)
         .context("正規表現が実行時間にマッチしません")?
@@ -90,7 +212,7 @@ fn parse_time(config: &Config, text: &String) -> Result<Time> {
         * config.parse_result.time_multiplier)
 }
 
-fn parse_score(config: &Config, text: &String) -> Result<Score> {
+fn parse_score(config: &Config, text: &str) -> Result<Score> {
     Ok(Regex::new(&config.parse_result.score_regex)?
         .captures(text)
         .context("正規表現がスコアにマッチしません")?
@@ -102,7 +224,7 @@ fn parse_score(config: &Config, text: &String) -> Result<Score> {
         * config.parse_result.score_multiplier)
 }
 
-fn parse_rate(config: &Config, text: &String) -> Result<Rate> {
+fn parse_rate(config: &Config, text: &str) -> Result<Rate> {
     Ok(Regex::new(&config.parse_result.rate_regex)?
         .captures(text)
         .context("正規表現が割合にマッチしません")?
@@ -123,16 +245,39 @@ pub struct FinalSummary {
     pub rates: Vec<Rate>,
     pub count: usize,
     pub ac_count: usize,
+    pub cases: Vec<CaseSummary>,
+    pub incomplete: bool,
+    /// AWS Lambda で実際に同時に実行できた数
+    pub concurrency: Option<Concurrency>,
+    /// AWS Lambda で実行したケースの合計
+    pub billing: Option<Billing>,
+    /// 指定すると費用の見積もりも表示する
+    pub price: Option<LambdaPriceConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Concurrency {
+    /// 時間で重みづけした平均
+    pub mean: f64,
+    pub peak: usize,
+    /// 終了時点の上限
+    pub limit: usize,
+    /// スロットリングされた回数
+    pub throttled: usize,
 }
 
 impl Display for FinalSummary {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let state_str: String = SubmissionStateSingle::try_from(self.state)
-            .and_then(|single| core::result::Result::Ok(single.to_string()))
+            .map(|single| single.to_string())
             .unwrap_or("???".into());
 
-        writeln!(f, "[提出@{}]", self.subm_id)?;
-        writeln!(f, "")?;
+        if self.incomplete {
+            writeln!(f, "[提出@{}] (中断・未完了)", self.subm_id)?;
+        } else {
+            writeln!(f, "[提出@{}]", self.subm_id)?;
+        }
+        writeln!(f)?;
         writeln!(f, "状態: {state_str} ({}/{})", self.ac_count, self.count)?;
         writeln!(
             f,
@@ -179,7 +324,32 @@ impl Display for FinalSummary {
                 * 100.
         )?;
         writeln!(f, "時間: {} ms", self.time * 1000.)?;
-        writeln!(f, "")
+        if let Some(c) = &self.concurrency {
+            writeln!(
+                f,
+                "並列: 平均 {:.1} / 最大 {} (終了時の上限 {}, スロットリング {} 回)",
+                c.mean, c.peak, c.limit, c.throttled
+            )?;
+        }
+        if let Some(billing) = &self.billing {
+            write!(
+                f,
+                "課金: {:.1} GB-s ({} 回, {} ms{})",
+                billing.gb_seconds,
+                billing.invocations,
+                comma_sep_int(billing.billed_ms.round() as i128),
+                if billing.estimated {
+                    ", 推定を含む"
+                } else {
+                    ""
+                }
+            )?;
+            if let Some(price) = &self.price {
+                write!(f, " 約 ${:.4}", billing.cost(price))?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f)
     }
 }
 
@@ -208,24 +378,300 @@ impl FinalSummary {
             rates: vec![],
             count: 0,
             ac_count: 0,
+            cases: vec![],
+            incomplete: false,
+            concurrency: None,
+            billing: None,
+            price: None,
         }
     }
 
-    pub fn next_case(&self, case: &CaseSummary) -> Self {
-        let mut scores = self.scores.clone();
-        scores.push(case.score);
+    pub fn load_dir(subm_id: u32, subm_dir: &Path, config: &Config) -> Result<Self> {
+        let casedirs = submission::case_dirs(subm_dir)?;
 
-        let mut rates = self.rates.clone();
-        rates.push(case.rate);
+        let mut summary = Self::zero(subm_id);
+        for (name, casedir) in &casedirs {
+            let case = CaseSummary::load_result(casedir).unwrap_or_else(|_| {
+                let mut case = CaseSummary::zero(name, AC as u32);
+                for file in &config.parse_result.files {
+                    if let Ok(s2) = &CaseSummary::parse_file(name, &casedir.join(file), config) {
+                        case = case.merge(s2);
+                    }
+                }
+                case
+            });
+            summary.next_case(&case);
+        }
 
-        Self {
-            subm_id: self.subm_id,
-            state: self.state | case.state,
-            time: self.time.max(case.time),
-            scores,
-            rates,
-            count: self.count + 1,
-            ac_count: self.ac_count + if case.state == AC as u32 { 1 } else { 0 },
+        Ok(summary)
+    }
+
+    /// 続きを実行した結果を加える。中断したケースの課金も rest.billing に入っているので、そちらを使う
+    pub fn extend(&mut self, rest: &Self) {
+        for case in &rest.cases {
+            self.push_case(case);
+        }
+        if let Some(billing) = &rest.billing {
+            self.add_billing(billing);
         }
+        self.incomplete = rest.incomplete;
+        self.concurrency = rest.concurrency.clone();
+    }
+
+    pub fn next_case(&mut self, case: &CaseSummary) {
+        self.push_case(case);
+        if let Some(billing) = &case.billing {
+            self.add_billing(billing);
+        }
+    }
+
+    /// 結果の残らなかった（中断した）ケースの課金も加える
+    pub fn add_billing(&mut self, billing: &Billing) {
+        self.billing = Some(match self.billing.take() {
+            Some(total) => total.add(billing),
+            None => billing.clone(),
+        });
+    }
+
+    fn push_case(&mut self, case: &CaseSummary) {
+        self.state |= case.state;
+        self.time = self.time.max(case.time);
+        self.scores.push(case.score);
+        self.rates.push(case.rate);
+        self.count += 1;
+        if case.state == AC as u32 {
+            self.ac_count += 1;
+        }
+        self.cases.push(case.clone());
+    }
+}
+
+/// 同じケースで実行した複数の提出を並べた表
+pub struct Comparison<'a> {
+    pub labels: &'a [String],
+    pub summaries: &'a [FinalSummary],
+    /// スコアが大きいほど良いなら true
+    pub maximize: bool,
+}
+
+impl Display for Comparison<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: BTreeSet<_> = self
+            .summaries
+            .iter()
+            .flat_map(|s| s.cases.iter().map(|case| case.name.as_str()))
+            .collect();
+
+        // 日本語の幅に合わせてそろえる
+        let pad = |text: &str, width| pad_str(text, width, Alignment::Center, None).into_owned();
+
+        write!(f, "{:10}", "")?;
+        for (label, summary) in self.labels.iter().zip(self.summaries) {
+            write!(
+                f,
+                "| {} ",
+                pad(&format!("{label} @{}", summary.subm_id), 22)
+            )?;
+        }
+        writeln!(f)?;
+
+        let mut best_counts = vec![0; self.summaries.len()];
+        for name in &names {
+            let cases: Vec<_> = self
+                .summaries
+                .iter()
+                .map(|s| s.cases.iter().find(|case| &case.name == name))
+                .collect();
+            if let Some(best) = unique_best(&cases, self.maximize) {
+                best_counts[best] += 1;
+            }
+
+            write!(f, "{}", pad(name, 10))?;
+            for case in &cases {
+                match case {
+                    Some(case) => write!(
+                        f,
+                        "| {:3} {:>15} pt ",
+                        SubmissionStateSingle::try_from(case.state)
+                            .map(|single| single.to_string())
+                            .unwrap_or("???".into()),
+                        comma_sep_int(case.score.round() as i128)
+                    )?,
+                    None => write!(f, "| {:^22} ", "-")?,
+                }
+            }
+            writeln!(f)?;
+        }
+
+        write!(f, "{}", pad("平均", 10))?;
+        for summary in self.summaries {
+            let mean = summary.scores.iter().sum::<f64>() / summary.count.max(1) as f64;
+            write!(f, "| {:>19} pt ", comma_sep_int(mean.round() as i128))?;
+        }
+        writeln!(f)?;
+
+        write!(f, "{}", pad("AC", 10))?;
+        for summary in self.summaries {
+            write!(
+                f,
+                "| {:>22} ",
+                format!("{}/{}", summary.ac_count, summary.count)
+            )?;
+        }
+        writeln!(f)?;
+
+        // 同点のケースは数えない
+        write!(f, "{}", pad("単独最高", 10))?;
+        for count in best_counts {
+            write!(f, "| {count:>22} ")?;
+        }
+        writeln!(f)
+    }
+}
+
+/// AC したなかでスコアが一番良い提出の位置。同点や AC がなければなし
+fn unique_best(cases: &[Option<&CaseSummary>], maximize: bool) -> Option<usize> {
+    let scores: Vec<_> = cases
+        .iter()
+        .map(|case| case.filter(|c| c.state == AC as u32).map(|c| c.score))
+        .collect();
+    let best = scores.iter().flatten().copied().max_by(|a, b| {
+        let order = a.partial_cmp(b).unwrap_or(Ordering::Equal);
+        if maximize {
+            order
+        } else {
+            order.reverse()
+        }
+    })?;
+    let mut bests = scores
+        .iter()
+        .enumerate()
+        .filter(|(_, score)| **score == Some(best));
+    let (i, _) = bests.next()?;
+    bests.next().is_none().then_some(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn case(state: SubmissionStateSingle, score: Score) -> CaseSummary {
+        CaseSummary {
+            name: "0000".into(),
+            state: state as u32,
+            time: 0.,
+            score,
+            rate: 0.,
+            billing: None,
+            exit_status: None,
+        }
+    }
+
+    #[test]
+    fn unique_best_follows_direction() {
+        let (a, b) = (case(AC, 10.), case(AC, 20.));
+        assert_eq!(unique_best(&[Some(&a), Some(&b)], true), Some(1));
+        assert_eq!(unique_best(&[Some(&a), Some(&b)], false), Some(0));
+        assert_eq!(unique_best(&[Some(&a), Some(&a.clone())], true), None);
+    }
+
+    #[test]
+    fn unique_best_ignores_non_ac() {
+        let (ac, wa) = (case(AC, 10.), case(WA, 20.));
+        assert_eq!(unique_best(&[Some(&ac), Some(&wa)], true), Some(0));
+        assert_eq!(unique_best(&[Some(&wa), None], true), None);
+        // 最小化でも、スコア 0 の WA は勝たない
+        let wa = case(WA, 0.);
+        assert_eq!(unique_best(&[Some(&ac), Some(&wa)], false), Some(0));
+    }
+
+    #[test]
+    fn billing_adds_up_per_case() {
+        let mut billing = Billing::default();
+        billing.add_invocation(1000., 1024, false);
+        billing.add_invocation(500., 2048, true);
+        assert_eq!(billing.invocations, 2);
+        assert_eq!(billing.billed_ms, 1500.);
+        assert_eq!(billing.gb_seconds, 2.);
+        assert!(billing.estimated);
+
+        let price = LambdaPriceConfig {
+            per_gb_second: 0.5,
+            per_request: 0.25,
+        };
+        assert_eq!(billing.cost(&price), 1.5);
+
+        let mut final_summary = FinalSummary::zero(1);
+        for _ in 0..2 {
+            final_summary.next_case(&CaseSummary {
+                billing: Some(billing.clone()),
+                ..case(AC, 1.)
+            });
+        }
+        // 結果の残らなかったケースの分も数える
+        final_summary.add_billing(&Billing {
+            invocations: 1,
+            ..Default::default()
+        });
+        let total = final_summary.billing.unwrap();
+        assert_eq!(total.invocations, 5);
+        assert_eq!(total.gb_seconds, 4.);
+        assert!(total.estimated);
+    }
+
+    fn config() -> Config {
+        Config::load(concat!(env!("CARGO_MANIFEST_DIR"), "/judge_config.yaml")).unwrap()
+    }
+
+    fn exited(code: i32) -> ExitStatus {
+        ExitStatus {
+            code: Some(code),
+            signal: None,
+            timed_out: false,
+            time: Some(0.5),
+        }
+    }
+
+    #[test]
+    fn reparses_stored_outputs_with_current_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let casedir = dir.path();
+        // 前回の比較の行は、解答の出力に [WA] があっても読まない
+        fs::write(
+            casedir.join("message.txt"),
+            format!(
+                "Score = 120\n{}出力が一致しません: [WA]\n",
+                compare::MESSAGE_PREFIX
+            ),
+        )
+        .unwrap();
+        fs::write(casedir.join("start_err.txt"), "real\t0m1.250s\n").unwrap();
+
+        let mut config = config();
+        let case = CaseSummary::parse_case("0000", casedir, &config, Ok(None));
+        assert_eq!(case.state, AC as u32);
+        assert_eq!((case.score, case.time), (120., 1.25));
+
+        config.parse_result.score_multiplier = 0.5;
+        let case = CaseSummary::parse_case("0000", casedir, &config, Ok(Some(exited(137))));
+        assert_eq!(case.score, 60.);
+        assert_eq!(case.state, MLE as u32);
+        // 保存された終了の仕方も、今の設定で状態に直す
+        config.parse_result.exit_status.codes.remove(&137);
+        let case = CaseSummary::parse_case("0000", casedir, &config, Ok(Some(exited(137))));
+        assert_eq!(case.state, RE as u32);
+        assert_eq!(case.exit_status, Some(exited(137)));
+
+        assert_eq!(
+            CaseSummary::parse_case("0000", casedir, &config, Err(())).state,
+            IE as u32
+        );
+    }
+
+    #[test]
+    fn measured_time_fills_missing_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let case = CaseSummary::parse_case("0000", dir.path(), &config(), Ok(Some(exited(0))));
+        assert_eq!((case.state, case.time), (AC as u32, 0.5));
     }
 }
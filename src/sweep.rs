@@ -0,0 +1,323 @@
+//! パラメータの探索。組み合わせごとに提出を作り、パラメータは環境変数で渡す
+
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::{cmp::Ordering, collections::BTreeMap, fmt::Display, time::SystemTime};
+
+use console::{pad_str, Alignment};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    comma_sep_int,
+    config::SweepConfig,
+    submission::Submission,
+    summary::{median_or_0, FinalSummary},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SweepStrategy {
+    /// values のすべての組み合わせ（budget 個まで）
+    #[default]
+    Grid,
+    /// budget 個の組み合わせを無作為に選ぶ
+    Random,
+    /// random と同じく選び、少ないケースで成績の悪い組み合わせから減らしていく
+    Halving,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SweepParam {
+    /// この中から選ぶ
+    Values { values: Vec<serde_yaml::Value> },
+    /// 一様分布。 log なら対数が一様、 int なら整数に丸める
+    Range {
+        min: f64,
+        max: f64,
+        #[serde(default)]
+        log: bool,
+        #[serde(default)]
+        int: bool,
+    },
+}
+
+pub type Params = BTreeMap<String, String>;
+
+/// 試す組み合わせ。数は SweepConfig::count と同じ
+pub fn combinations(sweep: &SweepConfig, seed: u64) -> Result<Vec<Params>> {
+    ensure!(sweep.budget > 0, "sweep.budget は 1 以上にしてください");
+    for (name, param) in &sweep.params {
+        match param {
+            SweepParam::Values { values } => {
+                ensure!(!values.is_empty(), "{name} の values が空です")
+            }
+            SweepParam::Range { min, max, log, .. } => {
+                ensure!(min <= max, "{name} の min が max より大きいです");
+                ensure!(
+                    !log || *min > 0.,
+                    "{name} は log なので min を正にしてください"
+                );
+            }
+        }
+    }
+
+    match sweep.strategy {
+        SweepStrategy::Grid => grid(sweep),
+        SweepStrategy::Random | SweepStrategy::Halving => {
+            let mut rng = Rng(seed);
+            Ok((0..sweep.budget)
+                .map(|_| {
+                    sweep
+                        .params
+                        .iter()
+                        .map(|(name, param)| (name.clone(), rng.sample(param)))
+                        .collect()
+                })
+                .collect())
+        }
+    }
+}
+
+fn grid(sweep: &SweepConfig) -> Result<Vec<Params>> {
+    let mut combinations = vec![Params::new()];
+    for (name, param) in &sweep.params {
+        let SweepParam::Values { values } = param else {
+            bail!("grid では {name} に values を指定してください");
+        };
+        combinations = combinations
+            .iter()
+            .flat_map(|params| {
+                values.iter().map(move |value| {
+                    let mut params = params.clone();
+                    params.insert(name.clone(), value_to_string(value));
+                    params
+                })
+            })
+            .take(sweep.budget)
+            .collect();
+    }
+    Ok(combinations)
+}
+
+/// 乱数の種。指定がなければ時刻から
+pub fn seed(sweep: &SweepConfig) -> u64 {
+    sweep.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default()
+    })
+}
+
+/// 同じ種で並べ替える。 halving で最初のほうのケースに偏りが出ないように
+pub fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut rng = Rng(seed ^ 0x5eed);
+    for i in (1..items.len()).rev() {
+        items.swap(i, rng.below(i + 1));
+    }
+}
+
+/// ラウンドごとの (残す組み合わせの数, そこまでに実行するケースの数)。最後のラウンドで全ケースになる
+pub fn rounds(sweep: &SweepConfig, combinations: usize, cases: usize) -> Vec<(usize, usize)> {
+    if sweep.strategy != SweepStrategy::Halving || combinations <= 1 {
+        return vec![(combinations, cases)];
+    }
+
+    let eta = sweep.eta.max(2);
+    let mut keeps = vec![combinations];
+    while *keeps.last().unwrap() > 1 {
+        keeps.push(keeps.last().unwrap().div_ceil(eta));
+    }
+
+    let n = keeps.len();
+    keeps
+        .into_iter()
+        .enumerate()
+        .map(|(r, keep)| {
+            let cases = cases
+                .div_ceil(eta.pow((n - 1 - r) as u32))
+                .max(1)
+                .min(cases);
+            (keep, cases)
+        })
+        .collect()
+}
+
+/// 良い順に並べた位置。実行したケースが多いほうを先にする
+pub fn rank(summaries: &[&FinalSummary], maximize: bool) -> Vec<usize> {
+    let mut order: Vec<_> = (0..summaries.len()).collect();
+    order.sort_by(|&a, &b| {
+        let (a, b) = (summaries[a], summaries[b]);
+        let score = mean(a).partial_cmp(&mean(b)).unwrap_or(Ordering::Equal);
+        b.count
+            .cmp(&a.count)
+            .then(if maximize { score.reverse() } else { score })
+    });
+    order
+}
+
+fn mean(summary: &FinalSummary) -> f64 {
+    summary.scores.iter().sum::<f64>() / summary.count.max(1) as f64
+}
+
+pub fn label(params: &Params) -> String {
+    params
+        .iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn value_to_string(value: &serde_yaml::Value) -> String {
+    match value {
+        serde_yaml::Value::String(s) => s.clone(),
+        serde_yaml::Value::Number(n) => n.to_string(),
+        serde_yaml::Value::Bool(b) => b.to_string(),
+        value => serde_yaml::to_string(value)
+            .unwrap_or_default()
+            .trim()
+            .to_owned(),
+    }
+}
+
+/// 探索の結果を良い順に並べた表
+pub struct Ranking<'a> {
+    pub submissions: &'a [Submission],
+    pub summaries: &'a [FinalSummary],
+    pub maximize: bool,
+}
+
+impl Display for Ranking<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let pad = |text: &str, width| pad_str(text, width, Alignment::Center, None).into_owned();
+        writeln!(
+            f,
+            "{}|{}|{}|{}|{}| パラメータ",
+            pad("順位", 6),
+            pad("提出", 8),
+            pad("AC", 9),
+            pad("平均", 18),
+            pad("中央", 18),
+        )?;
+
+        let summaries: Vec<_> = self.summaries.iter().collect();
+        for (place, i) in rank(&summaries, self.maximize).into_iter().enumerate() {
+            let (submission, summary) = (&self.submissions[i], &self.summaries[i]);
+            writeln!(
+                f,
+                "{:>5} | {:>6} | {:>7} | {:>13} pt | {:>13} pt | {}",
+                place + 1,
+                format!("@{}", summary.subm_id),
+                format!("{}/{}", summary.ac_count, summary.count),
+                comma_sep_int(mean(summary).round() as i128),
+                comma_sep_int(median_or_0(&summary.scores).round() as i128),
+                submission.label.as_deref().unwrap_or("")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// splitmix64 。再現できれば十分なので依存を増やさない
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        ((self.next_f64() * n as f64) as usize).min(n - 1)
+    }
+
+    fn sample(&mut self, param: &SweepParam) -> String {
+        match param {
+            SweepParam::Values { values } => value_to_string(&values[self.below(values.len())]),
+            SweepParam::Range { min, max, log, int } => {
+                let t = self.next_f64();
+                let x = if *log {
+                    (min.ln() + (max.ln() - min.ln()) * t).exp()
+                } else {
+                    min + (max - min) * t
+                };
+                if *int {
+                    (x.round() as i64).to_string()
+                } else {
+                    x.to_string()
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sweep(yaml: &str) -> SweepConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn grid_takes_budget_in_order() {
+        let config =
+            sweep("budget: 4\nparams:\n  A: { values: [1, 2] }\n  B: { values: [x, y, z] }\n");
+        assert_eq!(config.count(), 4);
+
+        let labels: Vec<_> = grid(&config).unwrap().iter().map(label).collect();
+        assert_eq!(labels, ["A=1 B=x", "A=1 B=y", "A=1 B=z", "A=2 B=x"]);
+
+        let config =
+            sweep("budget: 100\nparams:\n  A: { values: [1, 2] }\n  B: { values: [x, y, z] }\n");
+        assert_eq!(config.count(), 6);
+        assert_eq!(grid(&config).unwrap().len(), 6);
+    }
+
+    #[test]
+    fn grid_rejects_ranges() {
+        let config = sweep("budget: 4\nparams:\n  A: { min: 0, max: 1 }\n");
+        assert!(grid(&config).is_err());
+    }
+
+    #[test]
+    fn random_count_is_budget_and_seeded() {
+        let config =
+            sweep("strategy: random\nbudget: 5\nparams:\n  A: { min: 1, max: 1000, log: true }\n");
+        assert_eq!(config.count(), 5);
+        let first = combinations(&config, 42).unwrap();
+        assert_eq!(first.len(), 5);
+        assert_eq!(first, combinations(&config, 42).unwrap());
+    }
+
+    #[test]
+    fn rounds_halve_until_one() {
+        let config = sweep("strategy: halving\nbudget: 8\nparams: {}\n");
+        assert_eq!(
+            rounds(&config, 8, 100),
+            [(8, 13), (4, 25), (2, 50), (1, 100)]
+        );
+        assert_eq!(
+            rounds(&config, 5, 100),
+            [(5, 13), (3, 25), (2, 50), (1, 100)]
+        );
+        // ケースが少なくても各ラウンドで1ケースは実行する
+        assert_eq!(rounds(&config, 8, 3), [(8, 1), (4, 1), (2, 2), (1, 3)]);
+        assert_eq!(rounds(&config, 1, 100), [(1, 100)]);
+    }
+
+    #[test]
+    fn rounds_of_other_strategies_run_everything() {
+        let config = sweep("strategy: random\nbudget: 8\nparams: {}\n");
+        assert_eq!(rounds(&config, 8, 100), [(8, 100)]);
+    }
+}
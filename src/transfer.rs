@@ -0,0 +1,367 @@
+//! send / collect の from を展開して、ファイル・ディレクトリ・glob パターンを同じように扱う
+
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::{
+    collections::HashSet,
+    fs::{self, File, Metadata},
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt as _;
+
+use tar::{Archive, Builder, HeaderMode};
+
+/// 権限が取得できない環境（Windows）から送るファイルの権限。実行ファイルでも動くようにする
+pub const DEFAULT_MODE: u32 = 0o755;
+
+pub fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// glob パターンでもディレクトリでもない、1つのファイルか
+pub fn is_single_file(root: &Path, from: &Path) -> bool {
+    !is_glob(from) && root.join(from).is_file()
+}
+
+/// from を実際のファイルの一覧に展開する。
+/// 返り値は (ファイルのパス, to からの相対パス) で、単一ファイルなら相対パスは空
+pub fn resolve(root: &Path, from: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let full = root.join(from);
+
+    if is_glob(from) {
+        let base = root.join(glob_base(from));
+        let pattern = full.to_string_lossy();
+        let mut res = vec![];
+
+        for entry in
+            glob::glob(&pattern).with_context(|| format!("パターン {pattern} が誤っています"))?
+        {
+            let path = entry?;
+            let rel = path.strip_prefix(&base)?.to_path_buf();
+            if path.is_dir() {
+                for (file, rel2) in walk(&path)? {
+                    res.push((file, rel.join(rel2)));
+                }
+            } else {
+                res.push((path, rel));
+            }
+        }
+
+        ensure!(
+            !res.is_empty(),
+            "{pattern} にマッチするファイルがありません"
+        );
+        Ok(res)
+    } else if full.is_dir() {
+        walk(&full)
+    } else {
+        ensure!(full.is_file(), "ファイル {full:?} がありません");
+        Ok(vec![(full, PathBuf::new())])
+    }
+}
+
+/// from を to にコピーする。ディレクトリと glob パターンでは to をディレクトリとして扱う。
+/// mode を指定すると、コピーしたすべてのファイルの権限をそれに変える
+pub fn copy(
+    from_root: &Path,
+    from: &Path,
+    to_root: &Path,
+    to: &Path,
+    mode: Option<u32>,
+) -> Result<()> {
+    for (src, rel) in resolve(from_root, from)? {
+        let dest = if rel.as_os_str().is_empty() {
+            to_root.join(to)
+        } else {
+            to_root.join(to).join(rel)
+        };
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("ディレクトリ {parent:?} が作成できません"))?;
+        }
+        fs::copy(&src, &dest)
+            .with_context(|| format!("ファイル {src:?} を {dest:?} にコピーできません"))?;
+        if let Some(mode) = mode {
+            set_mode(&dest, mode)?;
+        }
+    }
+    Ok(())
+}
+
+/// from を tar にまとめる。同じ内容なら同じバイト列になるように、時刻や所有者は含めない。
+/// 権限は元のファイルのもの（mode を指定するとそれ、取得できなければ DEFAULT_MODE）が入る
+pub fn pack(root: &Path, from: &Path, mode: Option<u32>) -> Result<Vec<u8>> {
+    let mut builder = Builder::new(vec![]);
+    builder.mode(HeaderMode::Deterministic);
+
+    for (src, rel) in resolve(root, from)? {
+        let name = if rel.as_os_str().is_empty() {
+            PathBuf::from(src.file_name().context("ファイル名がありません")?)
+        } else {
+            rel
+        };
+        let mut file =
+            File::open(&src).with_context(|| format!("ファイル {src:?} が読み取れません"))?;
+        let metadata = file.metadata()?;
+
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, HeaderMode::Deterministic);
+        header.set_mode(
+            mode.or_else(|| file_mode(&metadata))
+                .unwrap_or(DEFAULT_MODE),
+        );
+        builder
+            .append_data(&mut header, &name, &mut file)
+            .with_context(|| format!("ファイル {src:?} を tar に追加できません"))?;
+    }
+
+    Ok(builder.into_inner()?)
+}
+
+pub fn unpack(data: &[u8], dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest).with_context(|| format!("ディレクトリ {dest:?} が作成できません"))?;
+    let mut archive = Archive::new(data);
+    archive.set_preserve_permissions(true);
+    archive
+        .unpack(dest)
+        .with_context(|| format!("tar を {dest:?} に展開できません"))
+}
+
+/// 権限のビット。 Windows など取得できない環境では None
+pub fn file_mode(metadata: &Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    return Some(metadata.permissions().mode() & 0o7777);
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+pub fn file_mtime(metadata: &Metadata) -> Option<i64> {
+    let modified = metadata.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64)
+}
+
+pub fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    #[cfg(unix)]
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("ファイル {path:?} の権限を {mode:o} に変更できません"))?;
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+pub fn set_mtime(path: &Path, mtime: i64) -> Result<()> {
+    let time = UNIX_EPOCH + Duration::from_secs(mtime.max(0) as u64);
+    File::options()
+        .write(true)
+        .open(path)
+        .and_then(|f| f.set_modified(time))
+        .with_context(|| format!("ファイル {path:?} の更新時刻を変更できません"))
+}
+
+/// 設定ファイルの "755" や "0o755" を8進数として読む
+pub fn parse_mode(s: &str) -> Result<u32> {
+    let digits = s.trim_start_matches("0o");
+    u32::from_str_radix(digits, 8).with_context(|| format!("権限 {s} が8進数ではありません"))
+}
+
+/// シンボリックリンクはたどる。リンクで同じディレクトリに戻ってくるときは2回目以降を飛ばす
+fn walk(dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut res = vec![];
+    let mut stack = vec![PathBuf::new()];
+    let mut visited = HashSet::new();
+
+    while let Some(rel) = stack.pop() {
+        let canonical = fs::canonicalize(dir.join(&rel))
+            .with_context(|| format!("ディレクトリ {:?} が見つかりません", dir.join(&rel)))?;
+        if !visited.insert(canonical) {
+            continue;
+        }
+        let mut entries: Vec<_> = fs::read_dir(dir.join(&rel))
+            .with_context(|| format!("ディレクトリ {:?} が読み込めません", dir.join(&rel)))?
+            .collect::<Result<_, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let rel = rel.join(entry.file_name());
+            // DirEntry::file_type はリンクをたどらないので、リンク先の種類を見る
+            let metadata = fs::metadata(entry.path())
+                .with_context(|| format!("{:?} のリンク先がありません", entry.path()))?;
+            if metadata.is_dir() {
+                stack.push(rel);
+            } else {
+                res.push((dir.join(&rel), rel));
+            }
+        }
+    }
+
+    res.sort();
+    Ok(res)
+}
+
+fn glob_base(pattern: &Path) -> PathBuf {
+    pattern
+        .components()
+        .take_while(|c| !matches!(c, Component::Normal(s) if is_glob(Path::new(s))))
+        .collect()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn walk_follows_symlinks() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("real/sub")).unwrap();
+        fs::write(root.join("real/sub/a.txt"), "a").unwrap();
+        fs::create_dir(root.join("from")).unwrap();
+        symlink(root.join("real/sub/a.txt"), root.join("from/file")).unwrap();
+        symlink(root.join("real"), root.join("from/dir")).unwrap();
+        // 自分自身を指すリンクでも止まる
+        symlink(root.join("from"), root.join("from/self")).unwrap();
+
+        let rels: Vec<_> = walk(&root.join("from"))
+            .unwrap()
+            .into_iter()
+            .map(|(_, rel)| rel)
+            .collect();
+        assert_eq!(
+            rels,
+            [PathBuf::from("dir/sub/a.txt"), PathBuf::from("file")]
+        );
+    }
+
+    fn tree(root: &Path) {
+        fs::create_dir_all(root.join("in/sub")).unwrap();
+        fs::write(root.join("in/a.txt"), "a").unwrap();
+        fs::write(root.join("in/b.dat"), "b").unwrap();
+        fs::write(root.join("in/sub/c.txt"), "c").unwrap();
+    }
+
+    fn rels(resolved: Vec<(PathBuf, PathBuf)>) -> Vec<PathBuf> {
+        resolved.into_iter().map(|(_, rel)| rel).collect()
+    }
+
+    #[test]
+    fn resolves_files_directories_and_globs() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        tree(root);
+
+        assert_eq!(
+            resolve(root, Path::new("in/a.txt")).unwrap(),
+            [(root.join("in/a.txt"), PathBuf::new())]
+        );
+        assert_eq!(
+            rels(resolve(root, Path::new("in")).unwrap()),
+            [
+                PathBuf::from("a.txt"),
+                PathBuf::from("b.dat"),
+                PathBuf::from("sub/c.txt")
+            ]
+        );
+        // glob では最初のパターンより前がディレクトリの基準になる
+        assert_eq!(
+            rels(resolve(root, Path::new("in/*.txt")).unwrap()),
+            [PathBuf::from("a.txt")]
+        );
+        assert_eq!(
+            rels(resolve(root, Path::new("in/s*")).unwrap()),
+            [PathBuf::from("sub/c.txt")]
+        );
+        assert!(resolve(root, Path::new("in/*.rs")).is_err());
+        assert!(resolve(root, Path::new("in/none.txt")).is_err());
+        assert!(is_single_file(root, Path::new("in/a.txt")));
+        assert!(!is_single_file(root, Path::new("in")));
+    }
+
+    #[test]
+    fn pack_unpack_round_trip() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        tree(root);
+
+        let packed = pack(root, Path::new("in"), None).unwrap();
+        // 時刻を含めないので、同じ内容なら同じバイト列になる
+        set_mtime(&root.join("in/a.txt"), 0).unwrap();
+        assert_eq!(pack(root, Path::new("in"), None).unwrap(), packed);
+
+        unpack(&packed, &root.join("out")).unwrap();
+        for name in ["a.txt", "b.dat", "sub/c.txt"] {
+            assert_eq!(
+                fs::read(root.join("out").join(name)).unwrap(),
+                fs::read(root.join("in").join(name)).unwrap()
+            );
+        }
+
+        // 単一ファイルはファイル名で入る
+        let packed = pack(root, Path::new("in/sub/c.txt"), None).unwrap();
+        unpack(&packed, &root.join("single")).unwrap();
+        assert_eq!(fs::read_to_string(root.join("single/c.txt")).unwrap(), "c");
+    }
+
+    #[test]
+    fn copy_keeps_relative_paths() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        tree(root);
+
+        copy(root, Path::new("in/*.txt"), root, Path::new("out"), None).unwrap();
+        copy(root, Path::new("in/b.dat"), root, Path::new("out/b"), None).unwrap();
+        assert_eq!(fs::read_to_string(root.join("out/a.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(root.join("out/b")).unwrap(), "b");
+        assert!(!root.join("out/sub").exists());
+    }
+
+    fn mode_of(path: &Path) -> u32 {
+        file_mode(&fs::metadata(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn modes_survive_pack_and_copy() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        tree(root);
+        set_mode(&root.join("in/a.txt"), 0o700).unwrap();
+        set_mode(&root.join("in/b.dat"), 0o640).unwrap();
+
+        unpack(
+            &pack(root, Path::new("in"), None).unwrap(),
+            &root.join("out"),
+        )
+        .unwrap();
+        assert_eq!(mode_of(&root.join("out/a.txt")), 0o700);
+        assert_eq!(mode_of(&root.join("out/b.dat")), 0o640);
+
+        // mode を指定するとすべてのファイルがそれになる
+        let packed = pack(root, Path::new("in"), Some(0o755)).unwrap();
+        unpack(&packed, &root.join("forced")).unwrap();
+        assert_eq!(mode_of(&root.join("forced/b.dat")), 0o755);
+        assert_eq!(mode_of(&root.join("forced/sub/c.txt")), 0o755);
+
+        copy(
+            root,
+            Path::new("in/b.dat"),
+            root,
+            Path::new("copied"),
+            Some(0o600),
+        )
+        .unwrap();
+        assert_eq!(mode_of(&root.join("copied")), 0o600);
+    }
+
+    #[test]
+    fn parses_octal_modes() {
+        assert_eq!(parse_mode("755").unwrap(), 0o755);
+        assert_eq!(parse_mode("0o644").unwrap(), 0o644);
+        assert!(parse_mode("9").is_err());
+    }
+}